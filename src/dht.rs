@@ -0,0 +1,667 @@
+//! A node of the Mainline DHT from BEP 5, for finding peers without a tracker.
+
+use crate::download::{DiscoveredPeer, PeerSource};
+use crate::token::WindowedToken;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use krpc::{Arguments, Message, Values};
+use ed25519_dalek::SigningKey;
+use routing::RoutingTable;
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use storage::{Item, Storage};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+pub use routing::{Node, K};
+pub use state::DhtState;
+pub use storage::{immutable_target, mutable_target, MutableItem, MAX_SALT_LEN, MAX_VALUE_LEN};
+
+mod krpc;
+mod lookup;
+mod routing;
+mod state;
+mod storage;
+
+/// The port DHT nodes conventionally listen on.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// Well-known nodes to join the DHT through when we know no other node.
+pub const BOOTSTRAP_NODES: &[(&str, u16)] = &[
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+
+/// How long we wait for a node to answer a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Least time the tokens we hand out for `announce_peer` stay valid.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// How long a peer announced to us is handed out without announcing again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Peers per `get_peers` response, which has to fit into a single datagram.
+const MAX_VALUES: usize = 50;
+
+/// Most torrents we keep announced peers for, and most peers we keep per torrent. Past either,
+/// the ones that announced longest ago make room.
+const MAX_TORRENTS: usize = 2048;
+const MAX_PEERS_PER_TORRENT: usize = 256;
+
+/// Buckets nobody has been seen in for this long are refreshed with a lookup.
+const REFRESH_AFTER: Duration = Duration::from_secs(15 * 60);
+
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a search looks for new peers of its torrent, and announces it again.
+const SEARCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How soon a search that failed, typically for lack of nodes, tries again.
+const SEARCH_RETRY: Duration = Duration::from_secs(30);
+
+const MAX_PACKET: usize = 2048;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DhtError {
+    #[error("dht i/o failed")]
+    Io(#[from] std::io::Error),
+    #[error("dht node did not answer in time")]
+    Timeout,
+    #[error("dht node answered with error {code}: {message}")]
+    Remote { code: i64, message: String },
+    #[error("dht node broke protocol: {0}")]
+    Protocol(String),
+    #[error("no dht node answered")]
+    NoNodes,
+    #[error("no dht node has the item")]
+    NotFound,
+    #[error("invalid dht item: {0}")]
+    InvalidItem(String),
+}
+
+/// A DHT node: answers other nodes' queries and looks up peers for us.
+///
+/// Background work stops when the node is dropped.
+pub struct Dht {
+    inner: Arc<Inner>,
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl Dht {
+    /// Starts a node on `addr`, with the id and the nodes of a saved `state` if there is one and
+    /// a random id otherwise. The node joins the network through `bootstrap` nodes, given as host
+    /// and port, whenever it knows no other node that answers.
+    pub async fn bind(
+        addr: SocketAddr,
+        bootstrap: Vec<(String, u16)>,
+        state: Option<DhtState>,
+    ) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let ipv4 = socket.local_addr()?.is_ipv4();
+        let id = state
+            .as_ref()
+            .and_then(DhtState::id)
+            .unwrap_or_else(rand::random);
+        let mut table = RoutingTable::new(id);
+        for (node, last_seen) in state.iter().flat_map(DhtState::nodes) {
+            if node.addr.is_ipv4() == ipv4 {
+                table.insert(node, last_seen);
+            }
+        }
+        let (pings, pings_rx) = mpsc::unbounded_channel();
+        let inner = Arc::new(Inner {
+            socket,
+            ipv4,
+            id,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(rand::random()),
+            tokens: WindowedToken::new(TOKEN_LIFETIME),
+            peers: Mutex::new(HashMap::new()),
+            storage: Mutex::new(Storage::default()),
+            bootstrap,
+            pings,
+        });
+        let mut tasks = JoinSet::new();
+        tasks.spawn(Arc::clone(&inner).receive());
+        tasks.spawn(Arc::clone(&inner).maintain());
+        tasks.spawn(Arc::clone(&inner).ping_questionable(pings_rx));
+        Ok(Self {
+            inner,
+            tasks: Mutex::new(tasks),
+        })
+    }
+
+    pub fn id(&self) -> [u8; 20] {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    /// Nodes in the routing table.
+    pub fn node_count(&self) -> usize {
+        self.inner.table().len()
+    }
+
+    /// Our id and the nodes we know, for [`bind`](Dht::bind) to pick up next time.
+    pub fn state(&self) -> DhtState {
+        DhtState::capture(self.inner.id, &self.inner.table())
+    }
+
+    /// Joins the network through the bootstrap nodes by looking up our own id, which fills the
+    /// routing table with our neighbours. Returns the number of nodes we know afterwards.
+    pub async fn bootstrap(&self) -> Result<usize, DhtError> {
+        self.inner.bootstrap().await
+    }
+
+    async fn ready(&self) -> Result<(), DhtError> {
+        if self.inner.needs_bootstrap() {
+            self.bootstrap().await?;
+        }
+        Ok(())
+    }
+
+    /// Looks up the peers of `info_hash`.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Result<Vec<SocketAddr>, DhtError> {
+        self.ready().await?;
+        let mut peers = Vec::new();
+        self.inner
+            .get_peers(info_hash, None, |found| peers.extend(found))
+            .await?;
+        Ok(peers)
+    }
+
+    /// Stores `value` on the nodes closest to its hash, which is returned.
+    pub async fn put_immutable(&self, value: Value) -> Result<[u8; 20], DhtError> {
+        storage::check_lengths(&value, &[])?;
+        let target = immutable_target(&value);
+        let item = Item::Immutable(value);
+        self.ready().await?;
+        let (_, closest) = self.inner.get_item(target, &[]).await?;
+        self.inner.put_item(closest, &item, None).await?;
+        Ok(target)
+    }
+
+    /// Fetches the value stored under its hash `target`.
+    pub async fn get_immutable(&self, target: [u8; 20]) -> Result<Value, DhtError> {
+        self.ready().await?;
+        match self.inner.get_item(target, &[]).await?.0 {
+            Some(Item::Immutable(value)) => Ok(value),
+            _ => Err(DhtError::NotFound),
+        }
+    }
+
+    /// Signs `value` with `key` and stores it on the nodes closest to the key and `salt`, as the
+    /// successor of the item stored there so far.
+    pub async fn put_mutable(&self, key: &SigningKey, salt: &[u8], value: Value) -> Result<MutableItem, DhtError> {
+        storage::check_lengths(&value, salt)?;
+        self.ready().await?;
+        let target = mutable_target(&key.verifying_key().to_bytes(), salt);
+        let (current, closest) = self.inner.get_item(target, salt).await?;
+        // Nodes only replace the version we saw, so that concurrent updates do not get lost.
+        let cas = match current {
+            Some(Item::Mutable(current)) => Some(current.seq),
+            _ => None,
+        };
+        let item = MutableItem::sign(key, salt.to_vec(), cas.map_or(1, |seq| seq + 1), value)?;
+        self.inner
+            .put_item(closest, &Item::Mutable(item.clone()), cas)
+            .await?;
+        Ok(item)
+    }
+
+    /// Fetches the latest item stored under `public_key` and `salt`.
+    pub async fn get_mutable(&self, public_key: &[u8; 32], salt: &[u8]) -> Result<MutableItem, DhtError> {
+        self.ready().await?;
+        match self.inner.get_item(mutable_target(public_key, salt), salt).await?.0 {
+            Some(Item::Mutable(item)) => Ok(item),
+            _ => Err(DhtError::NotFound),
+        }
+    }
+
+    /// Keeps looking up peers of `info_hash` in the background and sends them to `peers` until
+    /// its receiver is dropped. With `announce_port`, every lookup also tells the closest nodes
+    /// that we accept connections for the torrent on that port.
+    pub fn search(
+        &self,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        peers: mpsc::UnboundedSender<DiscoveredPeer>,
+    ) {
+        let inner = Arc::clone(&self.inner);
+        self.tasks
+            .lock()
+            .expect("dht task lock poisoned")
+            .spawn(inner.search(info_hash, announce_port, peers));
+    }
+}
+
+type Reply = oneshot::Sender<Result<Values, DhtError>>;
+
+struct Inner {
+    socket: UdpSocket,
+    /// The address family of the socket; the DHTs of IPv4 and IPv6 are separate networks.
+    ipv4: bool,
+    id: [u8; 20],
+    table: Mutex<RoutingTable>,
+    /// Where to hand replies to our queries, by transaction id, along with the address the
+    /// reply has to come from.
+    pending: Mutex<HashMap<u16, (SocketAddr, Reply)>>,
+    next_transaction: AtomicU16,
+    /// The tokens that `announce_peer` has to present.
+    tokens: WindowedToken,
+    /// Peers other nodes announced to us, by info hash, with when they did.
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddr, Instant>>>,
+    storage: Mutex<Storage>,
+    bootstrap: Vec<(String, u16)>,
+    /// Questionable nodes the routing table wants pinged before it replaces them.
+    pings: mpsc::UnboundedSender<Node>,
+}
+
+impl Inner {
+    fn table(&self) -> MutexGuard<'_, RoutingTable> {
+        self.table.lock().expect("dht routing table lock poisoned")
+    }
+
+    fn is_ipv4(&self) -> bool {
+        self.ipv4
+    }
+
+    fn storage(&self) -> MutexGuard<'_, Storage> {
+        self.storage.lock().expect("dht storage lock poisoned")
+    }
+
+    /// Records that `node` answered a query of ours or sent us one.
+    fn seen(&self, node: Node) {
+        if let Some(questionable) = self.table().seen(node) {
+            // Only fails once the node is shutting down.
+            let _ = self.pings.send(questionable);
+        }
+    }
+
+    /// Whether we know no node that is still worth asking.
+    fn needs_bootstrap(&self) -> bool {
+        self.table().closest(&self.id, 1).is_empty()
+    }
+
+    /// Arguments with just our id, for every query to start from.
+    fn arguments(&self) -> Arguments {
+        Arguments {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Arguments::default()
+        }
+    }
+
+    /// Return values with just our id, for every response to start from.
+    fn values(&self) -> Values {
+        Values {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Values::default()
+        }
+    }
+
+    /// Sends a query to `addr` and waits for the reply. Nodes that answer are added to the
+    /// routing table, and those that do not are marked as failing.
+    async fn query(&self, addr: SocketAddr, method: &str, arguments: Arguments) -> Result<Values, DhtError> {
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let (reply, reply_rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("dht pending lock poisoned")
+            .insert(transaction, (addr, reply));
+        let packet = Message::query(transaction.to_be_bytes(), method, arguments).encode();
+        let result = match self.socket.send_to(&packet, addr).await {
+            Ok(_) => match tokio::time::timeout(QUERY_TIMEOUT, reply_rx).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) | Err(_) => Err(DhtError::Timeout),
+            },
+            Err(e) => Err(e.into()),
+        };
+        self.pending
+            .lock()
+            .expect("dht pending lock poisoned")
+            .remove(&transaction);
+
+        match result {
+            Ok(values) => {
+                let id = krpc::node_id(&values.id)
+                    .ok_or_else(|| DhtError::Protocol("response carries no valid node id".to_string()))?;
+                self.seen(Node { id, addr });
+                Ok(values)
+            }
+            Err(DhtError::Timeout) => {
+                self.table().failed(addr);
+                Err(DhtError::Timeout)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The nodes of our address family in a response.
+    fn nodes_in(&self, values: &Values) -> Vec<Node> {
+        if self.is_ipv4() {
+            values.nodes.as_deref().map_or_else(Vec::new, |nodes| krpc::decode_nodes(nodes, 4))
+        } else {
+            values.nodes6.as_deref().map_or_else(Vec::new, |nodes| krpc::decode_nodes(nodes, 16))
+        }
+    }
+
+    async fn bootstrap(&self) -> Result<usize, DhtError> {
+        let mut queries = FuturesUnordered::new();
+        for (host, port) in &self.bootstrap {
+            // A bootstrap node that does not resolve is no worse than one that does not answer.
+            let Ok(addrs) = tokio::net::lookup_host((host.as_str(), *port)).await else {
+                continue;
+            };
+            for addr in addrs.filter(|addr| addr.is_ipv4() == self.is_ipv4()) {
+                let arguments = Arguments {
+                    target: Some(ByteBuf::from(self.id.to_vec())),
+                    ..self.arguments()
+                };
+                queries.push(async move { self.query(addr, "find_node", arguments).await });
+            }
+        }
+        let mut seed = Vec::new();
+        while let Some(result) = queries.next().await {
+            if let Ok(values) = result {
+                seed.extend(self.nodes_in(&values));
+            }
+        }
+        self.lookup(self.id, seed, "find_node", |_| {}).await?;
+        Ok(self.table().len())
+    }
+
+    /// Looks up the peers of `info_hash`, passing them to `found` as they come in, and announces
+    /// `announce_port` to the closest nodes if given.
+    async fn get_peers(
+        &self,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        mut found: impl FnMut(Vec<SocketAddr>),
+    ) -> Result<(), DhtError> {
+        let mut seen = HashSet::new();
+        let closest = self
+            .lookup(info_hash, Vec::new(), "get_peers", |values| {
+                let peers: Vec<SocketAddr> = values
+                    .values
+                    .iter()
+                    .flatten()
+                    .filter_map(|peer| krpc::decode_addr(peer))
+                    .filter(|peer| seen.insert(*peer))
+                    .collect();
+                if !peers.is_empty() {
+                    found(peers);
+                }
+            })
+            .await?;
+        let Some(port) = announce_port else {
+            return Ok(());
+        };
+        let announces = closest.into_iter().filter_map(|(node, token)| {
+            let arguments = Arguments {
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                token: Some(token?),
+                port: Some(port),
+                ..self.arguments()
+            };
+            Some(async move { self.query(node.addr, "announce_peer", arguments).await })
+        });
+        // Nodes that do not take the announce are no reason to fail the lookup.
+        futures_util::future::join_all(announces).await;
+        Ok(())
+    }
+
+    async fn search(
+        self: Arc<Self>,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        peers: mpsc::UnboundedSender<DiscoveredPeer>,
+    ) {
+        let searching = async {
+            loop {
+                if self.needs_bootstrap() {
+                    let _ = self.bootstrap().await;
+                }
+                let found = |found: Vec<SocketAddr>| {
+                    for addr in found {
+                        let _ = peers.send(DiscoveredPeer {
+                            addr,
+                            source: PeerSource::Dht,
+                        });
+                    }
+                };
+                let wait = match self.get_peers(info_hash, announce_port, found).await {
+                    Ok(()) => SEARCH_INTERVAL,
+                    // Everyone we knew has left, so start over from the bootstrap nodes.
+                    Err(DhtError::NoNodes) if self.bootstrap().await.is_ok() => continue,
+                    Err(_) => SEARCH_RETRY,
+                };
+                tokio::time::sleep(wait).await;
+            }
+        };
+        tokio::select! {
+            _ = peers.closed() => {}
+            _ = searching => {}
+        }
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_PACKET];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Some platforms report ICMP errors about earlier datagrams here; they say
+                // nothing about the socket itself.
+                Err(_) => continue,
+            };
+            let Ok(message) = Message::decode(&buf[..n]) else {
+                continue;
+            };
+            match message.y.as_str() {
+                krpc::QUERY => {
+                    let reply = self.handle_query(message, from);
+                    // The querying node asks again if the reply gets lost.
+                    let _ = self.socket.send_to(&reply.encode(), from).await;
+                }
+                krpc::RESPONSE | krpc::ERROR => self.dispatch(message, from),
+                _ => {}
+            }
+        }
+    }
+
+    /// Hands a response or error to the query waiting for it.
+    fn dispatch(&self, message: Message, from: SocketAddr) {
+        let Ok(transaction) = <[u8; 2]>::try_from(&message.t[..]) else {
+            return;
+        };
+        let transaction = u16::from_be_bytes(transaction);
+        let mut pending = self.pending.lock().expect("dht pending lock poisoned");
+        // A reply from anywhere else is most likely forged.
+        if pending.get(&transaction).is_none_or(|(addr, _)| *addr != from) {
+            return;
+        }
+        let (_, reply) = pending.remove(&transaction).expect("checked above");
+        let result = match (message.r, message.e) {
+            (Some(values), _) => Ok(values),
+            (None, Some((code, message))) => Err(DhtError::Remote { code, message }),
+            (None, None) => Err(DhtError::Protocol("reply carries neither values nor an error".to_string())),
+        };
+        let _ = reply.send(result);
+    }
+
+    fn handle_query(&self, message: Message, from: SocketAddr) -> Message {
+        let t = message.t;
+        let (Some(method), Some(arguments)) = (message.q, message.a) else {
+            return Message::error(t, krpc::ERROR_PROTOCOL, "query lacks a method or arguments");
+        };
+        let Some(id) = krpc::node_id(&arguments.id) else {
+            return Message::error(t, krpc::ERROR_PROTOCOL, "invalid node id");
+        };
+        self.seen(Node { id, addr: from });
+        let values = match method.as_str() {
+            "ping" => Ok(self.values()),
+            "find_node" => hash_argument(&arguments.target, "target").map(|target| self.closest_values(&target)),
+            "get_peers" => hash_argument(&arguments.info_hash, "info_hash").map(|info_hash| {
+                let mut values = self.closest_values(&info_hash);
+                values.token = Some(ByteBuf::from(self.tokens.issue(from.ip()).to_vec()));
+                let peers = self.stored_peers(&info_hash);
+                if !peers.is_empty() {
+                    values.values = Some(peers);
+                }
+                values
+            }),
+            "announce_peer" => self.announce_peer(&arguments, from),
+            "get" => hash_argument(&arguments.target, "target").map(|target| {
+                let mut values = self.closest_values(&target);
+                values.token = Some(ByteBuf::from(self.tokens.issue(from.ip()).to_vec()));
+                self.storage().fill(&target, &mut values, arguments.seq);
+                values
+            }),
+            "put" => {
+                let token = arguments.token.as_deref().map_or(&[][..], |token| &token[..]);
+                if self.tokens.is_valid(from.ip(), token) {
+                    self.storage().put(&arguments).map(|()| self.values())
+                } else {
+                    Err((krpc::ERROR_PROTOCOL, "invalid token".to_string()))
+                }
+            }
+            _ => Err((krpc::ERROR_METHOD_UNKNOWN, "method unknown".to_string())),
+        };
+        match values {
+            Ok(values) => Message::response(t, values),
+            Err((code, message)) => Message::error(t, code, &message),
+        }
+    }
+
+    /// Our id and the nodes we know closest to `target`.
+    fn closest_values(&self, target: &[u8; 20]) -> Values {
+        let (nodes, nodes6) = krpc::encode_nodes(&self.table().closest(target, K));
+        let mut values = self.values();
+        if self.is_ipv4() {
+            values.nodes = Some(ByteBuf::from(nodes));
+        } else {
+            values.nodes6 = Some(ByteBuf::from(nodes6));
+        }
+        values
+    }
+
+    fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<ByteBuf> {
+        let peers = self.peers.lock().expect("dht peer store lock poisoned");
+        let Some(peers) = peers.get(info_hash) else {
+            return Vec::new();
+        };
+        peers
+            .iter()
+            .filter(|(_, announced)| announced.elapsed() < PEER_TTL)
+            .take(MAX_VALUES)
+            .map(|(addr, _)| ByteBuf::from(krpc::encode_addr(*addr)))
+            .collect()
+    }
+
+    fn announce_peer(&self, arguments: &Arguments, from: SocketAddr) -> Result<Values, (i64, String)> {
+        let info_hash = hash_argument(&arguments.info_hash, "info_hash")?;
+        let token = arguments.token.as_deref().map_or(&[][..], |token| &token[..]);
+        if !self.tokens.is_valid(from.ip(), token) {
+            return Err((krpc::ERROR_PROTOCOL, "invalid token".to_string()));
+        }
+        let port = if arguments.implied_port == Some(1) {
+            from.port()
+        } else {
+            arguments
+                .port
+                .filter(|&port| port != 0)
+                .ok_or((krpc::ERROR_PROTOCOL, "missing port".to_string()))?
+        };
+        let mut torrents = self.peers.lock().expect("dht peer store lock poisoned");
+        if !torrents.contains_key(&info_hash) && torrents.len() >= MAX_TORRENTS {
+            let oldest = torrents
+                .iter()
+                .min_by_key(|(_, peers)| peers.values().max().copied())
+                .map(|(info_hash, _)| *info_hash);
+            if let Some(oldest) = oldest {
+                torrents.remove(&oldest);
+            }
+        }
+        let peers = torrents.entry(info_hash).or_default();
+        let addr = SocketAddr::new(from.ip(), port);
+        if !peers.contains_key(&addr) && peers.len() >= MAX_PEERS_PER_TORRENT {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, announced)| **announced)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(addr, Instant::now());
+        drop(torrents);
+        Ok(self.values())
+    }
+
+    /// Keeps the routing table fresh and forgets peers that stopped announcing.
+    /// Pings the questionable nodes the routing table asks about, so that the ones that do not
+    /// answer make room for newcomers.
+    async fn ping_questionable(self: Arc<Self>, mut nodes: mpsc::UnboundedReceiver<Node>) {
+        let mut pings = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                Some(node) = nodes.recv() => pings.push(self.ping(node)),
+                Some(()) = pings.next() => {}
+                else => break,
+            }
+        }
+    }
+
+    async fn ping(&self, node: Node) {
+        // Someone else answering from its address does not count.
+        let answered = match self.query(node.addr, "ping", self.arguments()).await {
+            Ok(values) => krpc::node_id(&values.id) == Some(node.id),
+            Err(_) => false,
+        };
+        if !answered {
+            self.table().ping_failed(&node);
+        }
+    }
+
+    async fn maintain(self: Arc<Self>) {
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + MAINTENANCE_INTERVAL,
+            MAINTENANCE_INTERVAL,
+        );
+        loop {
+            interval.tick().await;
+            self.peers
+                .lock()
+                .expect("dht peer store lock poisoned")
+                .retain(|_, peers| {
+                    peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+                    !peers.is_empty()
+                });
+            self.storage().expire();
+            if self.needs_bootstrap() {
+                let _ = self.bootstrap().await;
+                continue;
+            }
+            let targets = self.table().refresh_targets(REFRESH_AFTER);
+            for target in targets {
+                let _ = self.lookup(target, Vec::new(), "find_node", |_| {}).await;
+            }
+        }
+    }
+}
+
+fn hash_argument(argument: &Option<ByteBuf>, name: &str) -> Result<[u8; 20], (i64, String)> {
+    argument
+        .as_deref()
+        .and_then(|argument| krpc::node_id(argument))
+        .ok_or_else(|| (krpc::ERROR_PROTOCOL, format!("missing or invalid {name}")))
+}
+
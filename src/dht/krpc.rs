@@ -0,0 +1,196 @@
+//! KRPC, the bencoded query and response messages DHT nodes exchange over UDP.
+
+use super::routing::Node;
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub(super) const QUERY: &str = "q";
+pub(super) const RESPONSE: &str = "r";
+pub(super) const ERROR: &str = "e";
+
+pub(super) const ERROR_PROTOCOL: i64 = 203;
+pub(super) const ERROR_METHOD_UNKNOWN: i64 = 204;
+pub(super) const ERROR_VALUE_TOO_BIG: i64 = 205;
+pub(super) const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub(super) const ERROR_SALT_TOO_BIG: i64 = 207;
+pub(super) const ERROR_CAS_MISMATCH: i64 = 301;
+pub(super) const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+/// A query, a response or an error, told apart by `y`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Message {
+    /// Chosen by the querying node and echoed back in the reply.
+    pub t: ByteBuf,
+    pub y: String,
+    /// The method of a query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Arguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Values>,
+    /// Error code and message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+/// The arguments of a query; which ones are set depends on the method.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Arguments {
+    /// Node id of the querying node.
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Set to 1 when the announced port is the one the query came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+    /// The value of a `put`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>,
+    /// Public key of a mutable item.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    /// Signature of a mutable item.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    /// Sequence number of a mutable item in a `put`; in a `get`, the one the querying node
+    /// already has.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<ByteBuf>,
+    /// The sequence number a mutable item must have for a `put` to replace it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cas: Option<i64>,
+}
+
+/// The return values of a response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct Values {
+    /// Node id of the responding node.
+    pub id: ByteBuf,
+    /// Compact node info of IPv4 nodes: 20 bytes of id, 4 of address and 2 of port each.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    /// Compact node info of IPv6 nodes from BEP 32: 20 bytes of id, 16 of address and 2 of port
+    /// each.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// Peers in compact form, one string each.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    /// The stored value of an item, in response to `get`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+}
+
+impl Message {
+    pub fn query(t: [u8; 2], method: &str, arguments: Arguments) -> Self {
+        Self {
+            t: ByteBuf::from(t.to_vec()),
+            y: QUERY.to_string(),
+            q: Some(method.to_string()),
+            a: Some(arguments),
+            ..Self::default()
+        }
+    }
+
+    pub fn response(t: ByteBuf, values: Values) -> Self {
+        Self {
+            t,
+            y: RESPONSE.to_string(),
+            r: Some(values),
+            ..Self::default()
+        }
+    }
+
+    pub fn error(t: ByteBuf, code: i64, message: &str) -> Self {
+        Self {
+            t,
+            y: ERROR.to_string(),
+            e: Some((code, message.to_string())),
+            ..Self::default()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("krpc messages always encode")
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(packet)
+    }
+}
+
+/// A node id, if `id` is one.
+pub(super) fn node_id(id: &[u8]) -> Option<[u8; 20]> {
+    id.try_into().ok()
+}
+
+/// Encodes `nodes` in compact node info, IPv4 nodes into the first buffer and IPv6 nodes into
+/// the second.
+pub(super) fn encode_nodes(nodes: &[Node]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for node in nodes {
+        let out = if node.addr.is_ipv4() { &mut v4 } else { &mut v6 };
+        out.extend_from_slice(&node.id);
+        out.extend_from_slice(&encode_addr(node.addr));
+    }
+    (v4, v6)
+}
+
+/// Parses compact node info with `addr_len` bytes of address per node. Nodes with port 0 are
+/// left out since nobody can be reached there.
+pub(super) fn decode_nodes(nodes: &[u8], addr_len: usize) -> Vec<Node> {
+    nodes
+        .chunks_exact(20 + addr_len + 2)
+        .filter_map(|chunk| {
+            Some(Node {
+                id: chunk[..20].try_into().expect("chunk holds an id"),
+                addr: decode_addr(&chunk[20..]).filter(|addr| addr.port() != 0)?,
+            })
+        })
+        .collect()
+}
+
+/// The compact form of `addr`: its address bytes followed by the port in network byte order.
+pub(super) fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+pub(super) fn decode_addr(compact: &[u8]) -> Option<SocketAddr> {
+    let port = |at: usize| u16::from_be_bytes([compact[at], compact[at + 1]]);
+    match compact.len() {
+        6 => {
+            let ip: [u8; 4] = compact[..4].try_into().expect("length checked");
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(4)))
+        }
+        18 => {
+            let ip: [u8; 16] = compact[..16].try_into().expect("length checked");
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(16)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,129 @@
+//! Iterative lookups: asking ever closer nodes until the closest ones to a target are found.
+
+use super::krpc::{Arguments, Values};
+use super::routing::{distance, Node, K};
+use super::{DhtError, Inner};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Queries a lookup keeps in flight at once.
+const ALPHA: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    Unqueried,
+    Queried,
+    /// Answered, with the token it handed out if any.
+    Answered(Option<ByteBuf>),
+    Failed,
+}
+
+struct Candidate {
+    node: Node,
+    state: State,
+}
+
+impl Inner {
+    /// Walks towards `target` with `method`, which is `find_node`, `get_peers` or `get`, passing
+    /// every response along the way to `answered`. Starts from the closest nodes in the routing
+    /// table and from `seed`. Returns the closest nodes that answered, closest first, with the
+    /// tokens they handed out; it is an error if none did.
+    pub(super) async fn lookup(
+        &self,
+        target: [u8; 20],
+        seed: Vec<Node>,
+        method: &'static str,
+        mut answered: impl FnMut(&Values),
+    ) -> Result<Vec<(Node, Option<ByteBuf>)>, DhtError> {
+        let mut candidates: BTreeMap<[u8; 20], Candidate> = BTreeMap::new();
+        let start = self.table().closest(&target, K).into_iter().chain(seed);
+        for node in start {
+            self.add_candidate(&mut candidates, &target, node);
+        }
+        if candidates.is_empty() {
+            return Err(DhtError::NoNodes);
+        }
+
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            // Only the K closest nodes that still count are worth asking; once they have all
+            // answered, nobody closer is left to find.
+            while in_flight.len() < ALPHA {
+                let next = candidates
+                    .values_mut()
+                    .filter(|candidate| candidate.state != State::Failed)
+                    .take(K)
+                    .find(|candidate| candidate.state == State::Unqueried);
+                let Some(candidate) = next else {
+                    break;
+                };
+                candidate.state = State::Queried;
+                let node = candidate.node;
+                in_flight.push(async move { (node, self.lookup_query(node.addr, target, method).await) });
+            }
+            let Some((node, result)) = in_flight.next().await else {
+                break;
+            };
+            let key = distance(&node.id, &target);
+            let values = match result {
+                Ok(values) => values,
+                Err(_) => {
+                    if let Some(candidate) = candidates.get_mut(&key) {
+                        candidate.state = State::Failed;
+                    }
+                    continue;
+                }
+            };
+            if let Some(candidate) = candidates.get_mut(&key) {
+                candidate.state = State::Answered(values.token.clone());
+            }
+            for node in self.nodes_in(&values) {
+                self.add_candidate(&mut candidates, &target, node);
+            }
+            answered(&values);
+        }
+
+        let closest: Vec<_> = candidates
+            .into_values()
+            .filter_map(|candidate| match candidate.state {
+                State::Answered(token) => Some((candidate.node, token)),
+                _ => None,
+            })
+            .take(K)
+            .collect();
+        if closest.is_empty() {
+            return Err(DhtError::NoNodes);
+        }
+        Ok(closest)
+    }
+
+    fn add_candidate(&self, candidates: &mut BTreeMap<[u8; 20], Candidate>, target: &[u8; 20], node: Node) {
+        if node.id == self.id || node.addr.is_ipv4() != self.is_ipv4() {
+            return;
+        }
+        candidates
+            .entry(distance(&node.id, target))
+            .or_insert(Candidate {
+                node,
+                state: State::Unqueried,
+            });
+    }
+
+    async fn lookup_query(&self, addr: SocketAddr, target: [u8; 20], method: &str) -> Result<Values, DhtError> {
+        let target = Some(ByteBuf::from(target.to_vec()));
+        let arguments = if method == "get_peers" {
+            Arguments {
+                info_hash: target,
+                ..self.arguments()
+            }
+        } else {
+            Arguments {
+                target,
+                ..self.arguments()
+            }
+        };
+        self.query(addr, method, arguments).await
+    }
+}
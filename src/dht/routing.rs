@@ -0,0 +1,350 @@
+//! The routing table: the nodes we know, kept in k-buckets by their distance from our id.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Nodes per bucket, and how many of the closest nodes a lookup converges on.
+pub const K: usize = 8;
+
+/// A node that has not been heard from for this long may have left; it is the first to go when
+/// its bucket is full.
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Unanswered queries in a row after which a node is considered bad.
+const MAX_FAILURES: u32 = 2;
+
+/// One bucket per bit of the id, so that every possible distance has a place.
+const BUCKETS: usize = 160;
+
+/// How long a questionable node gets to answer our ping before another one may be pinged.
+const PING_PATIENCE: Duration = Duration::from_secs(10);
+
+/// A DHT node as other nodes tell us about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+}
+
+/// The XOR metric of Kademlia: the smaller the result, the closer `a` and `b` are.
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Entry {
+    pub node: Node,
+    pub last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    fn is_questionable(&self) -> bool {
+        self.failures > 0 || self.last_seen.elapsed() >= QUESTIONABLE_AFTER
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    entries: Vec<Entry>,
+    last_changed: Instant,
+    /// A node that wants in while the bucket is full, waiting on a questionable node to answer.
+    replacement: Option<Replacement>,
+}
+
+#[derive(Debug, Clone)]
+struct Replacement {
+    entry: Entry,
+    /// The questionable node we pinged, which `entry` replaces unless it answers.
+    pinged: [u8; 20],
+    since: Instant,
+}
+
+#[derive(Debug)]
+pub(super) struct RoutingTable {
+    own_id: [u8; 20],
+    /// Bucket `i` holds the nodes whose id shares exactly the first `i` bits with ours.
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: [u8; 20]) -> Self {
+        let bucket = Bucket {
+            entries: Vec::with_capacity(K),
+            last_changed: Instant::now(),
+            replacement: None,
+        };
+        Self {
+            own_id,
+            buckets: vec![bucket; BUCKETS],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.entries.len()).sum()
+    }
+
+    fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let byte = distance.iter().position(|&b| b != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+
+    /// Records that `node` answered a query of ours or sent us one. Returns a node to ping, see
+    /// [`insert`](Self::insert).
+    pub fn seen(&mut self, node: Node) -> Option<Node> {
+        self.insert(node, Instant::now())
+    }
+
+    /// Adds `node`, last heard from at `last_seen`. When its bucket is full, new nodes take the
+    /// place of bad ones. Questionable ones first get to prove they are still around: the one to
+    /// ping is returned, and the new node only takes its place if it does not answer, see
+    /// [`ping_failed`](Self::ping_failed). Otherwise new nodes are left out, since nodes that have
+    /// been around for long tend to stay.
+    pub fn insert(&mut self, node: Node, last_seen: Instant) -> Option<Node> {
+        let index = self.bucket_index(&node.id)?;
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.entries.iter_mut().find(|entry| entry.node.id == node.id) {
+            // Someone else claiming a known id does not get to take over its entry.
+            if entry.node.addr == node.addr {
+                entry.last_seen = entry.last_seen.max(last_seen);
+                entry.failures = 0;
+                bucket.last_changed = Instant::now();
+                // It answered, so the node waiting for its place stays out.
+                if bucket.replacement.as_ref().is_some_and(|replacement| replacement.pinged == node.id) {
+                    bucket.replacement = None;
+                }
+            }
+            return None;
+        }
+        let entry = Entry {
+            node,
+            last_seen,
+            failures: 0,
+        };
+        if bucket.entries.len() < K {
+            bucket.entries.push(entry);
+            bucket.last_changed = Instant::now();
+            return None;
+        }
+        if let Some(worst) = bucket
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.is_bad())
+            .max_by_key(|entry| (entry.failures, entry.last_seen.elapsed()))
+        {
+            *worst = entry;
+            bucket.last_changed = Instant::now();
+            return None;
+        }
+        let questionable = bucket
+            .entries
+            .iter()
+            .filter(|entry| entry.is_questionable())
+            .max_by_key(|entry| (entry.failures, entry.last_seen.elapsed()))?
+            .node;
+        // One ping per bucket at a time; the latest node to come along waits for its outcome.
+        if let Some(replacement) = &mut bucket.replacement {
+            if replacement.since.elapsed() < PING_PATIENCE {
+                replacement.entry = entry;
+                return None;
+            }
+        }
+        bucket.replacement = Some(Replacement {
+            entry,
+            pinged: questionable.id,
+            since: Instant::now(),
+        });
+        Some(questionable)
+    }
+
+    /// Records that `node`, which [`insert`](Self::insert) asked us to ping, did not answer, so
+    /// the node waiting for its place takes it.
+    pub fn ping_failed(&mut self, node: &Node) {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if bucket.replacement.as_ref().is_none_or(|replacement| replacement.pinged != node.id) {
+            return;
+        }
+        let replacement = bucket.replacement.take().expect("checked above");
+        if bucket.entries.iter().any(|entry| entry.node.id == replacement.entry.node.id) {
+            return;
+        }
+        if let Some(entry) = bucket.entries.iter_mut().find(|entry| entry.node.id == node.id) {
+            *entry = replacement.entry;
+            bucket.last_changed = Instant::now();
+        }
+    }
+
+    /// Records that the node at `addr` did not answer.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            for entry in &mut bucket.entries {
+                if entry.node.addr == addr {
+                    entry.failures += 1;
+                }
+            }
+        }
+    }
+
+    /// Up to `n` nodes closest to `target` that are not bad, closest first.
+    pub fn closest(&self, target: &[u8; 20], n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self
+            .entries()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.buckets.iter().flat_map(|bucket| bucket.entries.iter())
+    }
+
+    /// A random id in every non-empty bucket that has not changed for `age`; looking them up
+    /// refreshes the buckets.
+    pub fn refresh_targets(&self, age: Duration) -> Vec<[u8; 20]> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.entries.is_empty() && bucket.last_changed.elapsed() >= age)
+            .map(|(index, _)| self.random_id_in(index))
+            .collect()
+    }
+
+    /// An id that shares exactly the first `index` bits with ours.
+    fn random_id_in(&self, index: usize) -> [u8; 20] {
+        let mut id: [u8; 20] = rand::random();
+        let (byte, bit) = (index / 8, index % 8);
+        id[..byte].copy_from_slice(&self.own_id[..byte]);
+        let keep = !(0xffu8 >> bit);
+        let flip = 0x80u8 >> bit;
+        id[byte] = (self.own_id[byte] & keep) | (!self.own_id[byte] & flip) | (id[byte] & !(keep | flip));
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: [u8; 20], port: u16) -> Node {
+        Node {
+            id,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    /// An id that differs from `own_id` in bit `i` only.
+    fn flip(own_id: [u8; 20], i: usize) -> [u8; 20] {
+        let mut id = own_id;
+        id[i / 8] ^= 0x80 >> (i % 8);
+        id
+    }
+
+    #[test]
+    fn bucket_index_counts_shared_prefix_bits() {
+        let own_id = [0x5a; 20];
+        let table = RoutingTable::new(own_id);
+        assert_eq!(table.bucket_index(&own_id), None);
+        for i in [0, 1, 7, 8, 13, 159] {
+            assert_eq!(table.bucket_index(&flip(own_id, i)), Some(i));
+        }
+        // Only the first differing bit counts.
+        let mut id = flip(own_id, 9);
+        id[19] ^= 0xff;
+        assert_eq!(table.bucket_index(&id), Some(9));
+    }
+
+    #[test]
+    fn random_ids_land_in_their_bucket() {
+        let table = RoutingTable::new(rand::random());
+        for index in [0, 5, 8, 100, 159] {
+            assert_eq!(table.bucket_index(&table.random_id_in(index)), Some(index));
+        }
+    }
+
+    /// A table with a full bucket 0, holding the nodes on ports 0 to `K - 1`.
+    fn full_table() -> RoutingTable {
+        let mut table = RoutingTable::new([0; 20]);
+        // All of these share no bits with our id, so they go into bucket 0.
+        for port in 0..K as u16 {
+            table.seen(node(bucket_0_id(port as u8), port));
+        }
+        table
+    }
+
+    fn bucket_0_id(last: u8) -> [u8; 20] {
+        let mut id = [0xff; 20];
+        id[19] = last;
+        id
+    }
+
+    #[test]
+    fn full_buckets_keep_good_nodes() {
+        let mut table = full_table();
+        assert_eq!(table.seen(node(bucket_0_id(0xee), 100)), None);
+        assert_eq!(table.len(), K);
+        assert!(table.entries().all(|entry| entry.node.addr.port() < K as u16));
+    }
+
+    #[test]
+    fn questionable_nodes_make_room_when_they_do_not_answer() {
+        let mut table = full_table();
+        let questionable = node(bucket_0_id(0), 0);
+        table.failed(questionable.addr);
+        assert_eq!(table.seen(node(bucket_0_id(0xee), 100)), Some(questionable));
+        // Nothing changes until the ping is over.
+        assert!(table.entries().any(|entry| entry.node == questionable));
+
+        table.ping_failed(&questionable);
+        assert_eq!(table.len(), K);
+        assert!(table.entries().all(|entry| entry.node != questionable));
+        assert!(table.entries().any(|entry| entry.node.addr.port() == 100));
+    }
+
+    #[test]
+    fn questionable_nodes_that_answer_stay() {
+        let mut table = full_table();
+        let questionable = node(bucket_0_id(0), 0);
+        table.failed(questionable.addr);
+        assert_eq!(table.seen(node(bucket_0_id(0xee), 100)), Some(questionable));
+
+        assert_eq!(table.seen(questionable), None);
+        // A late timeout of the same ping no longer evicts it.
+        table.ping_failed(&questionable);
+        assert!(table.entries().any(|entry| entry.node == questionable));
+        assert!(table.entries().all(|entry| entry.node.addr.port() != 100));
+    }
+
+    #[test]
+    fn bad_nodes_are_replaced_right_away() {
+        let mut table = full_table();
+        for _ in 0..MAX_FAILURES {
+            table.failed(node(bucket_0_id(0), 0).addr);
+        }
+        assert_eq!(table.seen(node(bucket_0_id(0xee), 100)), None);
+        assert_eq!(table.len(), K);
+        assert!(table.entries().all(|entry| entry.node.addr.port() != 0));
+    }
+
+    #[test]
+    fn closest_sorts_by_distance() {
+        let own_id = [0; 20];
+        let mut table = RoutingTable::new(own_id);
+        for i in [3, 50, 120] {
+            table.seen(node(flip(own_id, i), i as u16));
+        }
+        let target = flip(own_id, 120);
+        let ports: Vec<_> = table.closest(&target, 2).iter().map(|node| node.addr.port()).collect();
+        assert_eq!(ports, [120, 50]);
+    }
+}
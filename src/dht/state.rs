@@ -0,0 +1,99 @@
+//! What a node saves between runs so that it can rejoin the network without bootstrapping.
+
+use super::krpc;
+use super::routing::{Node, RoutingTable};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Saved nodes last heard from longer ago than this have most likely left the network.
+const MAX_NODE_AGE: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Our node id and the nodes of our routing table, as saved to disk in bencode.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DhtState {
+    id: ByteBuf,
+    nodes: Vec<SavedNode>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedNode {
+    id: ByteBuf,
+    /// In compact form.
+    addr: ByteBuf,
+    /// Seconds since the Unix epoch.
+    last_seen: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl DhtState {
+    pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let f = tokio::fs::read(file).await.context("open dht state file")?;
+        let state: DhtState = serde_bencode::from_bytes(&f).context("parse dht state file")?;
+        Ok(state)
+    }
+
+    /// Replaces `file` in one step, so that a run that gets killed halfway leaves the previous
+    /// state intact.
+    pub async fn write(&self, file: impl AsRef<Path>) -> anyhow::Result<()> {
+        let file = file.as_ref();
+        let encoded = serde_bencode::to_bytes(self).context("encode dht state")?;
+        let mut temporary = file.as_os_str().to_owned();
+        temporary.push(".tmp");
+        tokio::fs::write(&temporary, encoded)
+            .await
+            .context("write dht state file")?;
+        tokio::fs::rename(&temporary, file)
+            .await
+            .context("replace dht state file")
+    }
+
+    /// The node id we had, if the state holds a valid one.
+    pub fn id(&self) -> Option<[u8; 20]> {
+        krpc::node_id(&self.id)
+    }
+
+    /// Saved nodes recent enough to be worth trying, with when they were last seen.
+    pub(super) fn nodes(&self) -> Vec<(Node, Instant)> {
+        let now = unix_now();
+        self.nodes
+            .iter()
+            .filter_map(|saved| {
+                // A clock that went backwards makes nodes look fresh rather than ancient.
+                let age = Duration::from_secs(now.saturating_sub(saved.last_seen));
+                if age > MAX_NODE_AGE {
+                    return None;
+                }
+                let node = Node {
+                    id: krpc::node_id(&saved.id)?,
+                    addr: krpc::decode_addr(&saved.addr)?,
+                };
+                Some((node, Instant::now().checked_sub(age).unwrap_or_else(Instant::now)))
+            })
+            .collect()
+    }
+
+    pub(super) fn capture(id: [u8; 20], table: &RoutingTable) -> Self {
+        let now = unix_now();
+        Self {
+            id: ByteBuf::from(id.to_vec()),
+            nodes: table
+                .entries()
+                .filter(|entry| !entry.is_bad())
+                .map(|entry| SavedNode {
+                    id: ByteBuf::from(entry.node.id.to_vec()),
+                    addr: ByteBuf::from(krpc::encode_addr(entry.node.addr)),
+                    last_seen: now.saturating_sub(entry.last_seen.elapsed().as_secs()),
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Storage of arbitrary data from BEP 44: immutable items stored under the hash of their value,
+//! and mutable items signed with an ed25519 key and stored under the hash of that key.
+
+use super::krpc::{self, Arguments, Values};
+use super::routing::Node;
+use super::{DhtError, Inner};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Largest value an item may hold, bencoded.
+pub const MAX_VALUE_LEN: usize = 1000;
+
+pub const MAX_SALT_LEN: usize = 64;
+
+/// How long we keep an item that nobody stores again.
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Most items we keep for other nodes; with values of at most [`MAX_VALUE_LEN`] bytes, that
+/// bounds what they can make us hold to a few megabytes.
+const MAX_ITEMS: usize = 4096;
+
+/// An item its owner can update by signing a new value with a higher sequence number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableItem {
+    pub value: Value,
+    pub public_key: [u8; 32],
+    /// Lets one key publish several items.
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    pub fn sign(key: &SigningKey, salt: Vec<u8>, seq: i64, value: Value) -> Result<Self, DhtError> {
+        check_lengths(&value, &salt)?;
+        let signature = key.sign(&signed_data(&salt, seq, &value)).to_bytes();
+        Ok(Self {
+            value,
+            public_key: key.verifying_key().to_bytes(),
+            salt,
+            seq,
+            signature,
+        })
+    }
+
+    pub fn target(&self) -> [u8; 20] {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    /// An item from the fields of a message, if they have the right lengths.
+    fn from_fields(value: Value, public_key: &[u8], salt: &[u8], seq: Option<i64>, signature: Option<&ByteBuf>) -> Option<Self> {
+        Some(Self {
+            value,
+            public_key: public_key.try_into().ok()?,
+            salt: salt.to_vec(),
+            seq: seq?,
+            signature: signature?.as_slice().try_into().ok()?,
+        })
+    }
+
+    /// Whether the signature is the owner's.
+    pub fn verify(&self) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.public_key) else {
+            return false;
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        key.verify(&signed_data(&self.salt, self.seq, &self.value), &signature)
+            .is_ok()
+    }
+}
+
+/// Where an immutable item with `value` is stored: the SHA-1 of the bencoded value.
+pub fn immutable_target(value: &Value) -> [u8; 20] {
+    Sha1::digest(encode(value)).into()
+}
+
+/// Where the mutable items of `public_key` with `salt` are stored.
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+fn encode(value: &Value) -> Vec<u8> {
+    serde_bencode::to_bytes(value).expect("bencode values always encode")
+}
+
+pub(super) fn check_lengths(value: &Value, salt: &[u8]) -> Result<(), DhtError> {
+    if encode(value).len() > MAX_VALUE_LEN {
+        return Err(DhtError::InvalidItem(format!("value is longer than {MAX_VALUE_LEN} bytes")));
+    }
+    if salt.len() > MAX_SALT_LEN {
+        return Err(DhtError::InvalidItem(format!("salt is longer than {MAX_SALT_LEN} bytes")));
+    }
+    Ok(())
+}
+
+/// What the owner of a mutable item signs: the item's fields as they would appear in a bencoded
+/// dictionary, without the surrounding `d` and `e`.
+fn signed_data(salt: &[u8], seq: i64, value: &Value) -> Vec<u8> {
+    let mut data = Vec::new();
+    if !salt.is_empty() {
+        data.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend_from_slice(salt);
+    }
+    data.extend_from_slice(format!("3:seqi{seq}e1:v").as_bytes());
+    data.extend_from_slice(&encode(value));
+    data
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Item {
+    Immutable(Value),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    /// The item stored in a `get` response, if it is the one stored at `target` and checks out.
+    fn from_values(values: &Values, target: &[u8; 20], salt: &[u8]) -> Option<Self> {
+        let value = values.v.clone()?;
+        let Some(public_key) = &values.k else {
+            return (immutable_target(&value) == *target).then_some(Item::Immutable(value));
+        };
+        let item = MutableItem::from_fields(value, public_key, salt, values.seq, values.sig.as_ref())?;
+        (item.target() == *target && item.verify()).then_some(Item::Mutable(item))
+    }
+
+    /// Adds the item to a `get` response. Nodes that already have sequence number `seq` of a
+    /// mutable item, or a later one, only get told the current sequence number.
+    fn fill(&self, values: &mut Values, seq: Option<i64>) {
+        match self {
+            Item::Immutable(value) => values.v = Some(value.clone()),
+            Item::Mutable(item) => {
+                values.seq = Some(item.seq);
+                if seq.is_some_and(|seq| seq >= item.seq) {
+                    return;
+                }
+                values.v = Some(item.value.clone());
+                values.k = Some(ByteBuf::from(item.public_key.to_vec()));
+                values.sig = Some(ByteBuf::from(item.signature.to_vec()));
+            }
+        }
+    }
+}
+
+/// The items other nodes stored with us, by target, with when they last did.
+#[derive(Debug, Default)]
+pub(super) struct Storage {
+    items: HashMap<[u8; 20], (Item, Instant)>,
+}
+
+impl Storage {
+    pub fn fill(&self, target: &[u8; 20], values: &mut Values, seq: Option<i64>) {
+        if let Some((item, stored)) = self.items.get(target) {
+            if stored.elapsed() < ITEM_TTL {
+                item.fill(values, seq);
+            }
+        }
+    }
+
+    /// Validates and stores the item of a `put`, answering with a KRPC error code otherwise.
+    pub fn put(&mut self, arguments: &Arguments) -> Result<(), (i64, String)> {
+        let value = arguments
+            .v
+            .clone()
+            .ok_or((krpc::ERROR_PROTOCOL, "missing v".to_string()))?;
+        if encode(&value).len() > MAX_VALUE_LEN {
+            return Err((krpc::ERROR_VALUE_TOO_BIG, "message (v field) too big".to_string()));
+        }
+        let Some(public_key) = &arguments.k else {
+            self.insert(immutable_target(&value), Item::Immutable(value));
+            return Ok(());
+        };
+
+        let salt = arguments.salt.as_deref().map_or(&[][..], |salt| &salt[..]);
+        if salt.len() > MAX_SALT_LEN {
+            return Err((krpc::ERROR_SALT_TOO_BIG, "salt (salt field) too big".to_string()));
+        }
+        let item = MutableItem::from_fields(value, public_key, salt, arguments.seq, arguments.sig.as_ref())
+            .ok_or((krpc::ERROR_PROTOCOL, "missing or invalid k, seq or sig".to_string()))?;
+        if !item.verify() {
+            return Err((krpc::ERROR_INVALID_SIGNATURE, "invalid signature".to_string()));
+        }
+        let target = item.target();
+        if let Some((Item::Mutable(current), _)) = self.items.get(&target) {
+            if arguments.cas.is_some_and(|cas| cas != current.seq) {
+                return Err((krpc::ERROR_CAS_MISMATCH, "CAS mismatch".to_string()));
+            }
+            // The same sequence number is fine for storing the same value again.
+            if item.seq < current.seq || (item.seq == current.seq && item.value != current.value) {
+                return Err((krpc::ERROR_SEQ_TOO_LOW, "sequence number less than current".to_string()));
+            }
+        }
+        self.insert(target, Item::Mutable(item));
+        Ok(())
+    }
+
+    /// Stores `item`, making room by dropping the item stored longest ago if we are full.
+    fn insert(&mut self, target: [u8; 20], item: Item) {
+        if !self.items.contains_key(&target) && self.items.len() >= MAX_ITEMS {
+            self.expire();
+            let oldest = self
+                .items
+                .iter()
+                .min_by_key(|(_, (_, stored))| *stored)
+                .map(|(target, _)| *target);
+            if let Some(oldest) = oldest.filter(|_| self.items.len() >= MAX_ITEMS) {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(target, (item, Instant::now()));
+    }
+
+    pub fn expire(&mut self) {
+        self.items.retain(|_, (_, stored)| stored.elapsed() < ITEM_TTL);
+    }
+}
+
+impl Inner {
+    /// Looks up the item at `target`, the latest version for mutable items. Returns it, if any
+    /// node had it, along with the closest nodes and their tokens for a `put`.
+    pub(super) async fn get_item(
+        &self,
+        target: [u8; 20],
+        salt: &[u8],
+    ) -> Result<(Option<Item>, Vec<(Node, Option<ByteBuf>)>), DhtError> {
+        let mut found: Option<Item> = None;
+        let closest = self
+            .lookup(target, Vec::new(), "get", |values| {
+                let Some(item) = Item::from_values(values, &target, salt) else {
+                    return;
+                };
+                let newer = match (&found, &item) {
+                    (Some(Item::Mutable(current)), Item::Mutable(item)) => item.seq > current.seq,
+                    (found, _) => found.is_none(),
+                };
+                if newer {
+                    found = Some(item);
+                }
+            })
+            .await?;
+        Ok((found, closest))
+    }
+
+    /// Stores `item` on the `closest` nodes. Succeeds if at least one of them took it.
+    pub(super) async fn put_item(
+        &self,
+        closest: Vec<(Node, Option<ByteBuf>)>,
+        item: &Item,
+        cas: Option<i64>,
+    ) -> Result<usize, DhtError> {
+        let mut arguments = self.arguments();
+        match item {
+            Item::Immutable(value) => arguments.v = Some(value.clone()),
+            Item::Mutable(item) => {
+                arguments.v = Some(item.value.clone());
+                arguments.k = Some(ByteBuf::from(item.public_key.to_vec()));
+                arguments.sig = Some(ByteBuf::from(item.signature.to_vec()));
+                arguments.seq = Some(item.seq);
+                arguments.salt = (!item.salt.is_empty()).then(|| ByteBuf::from(item.salt.clone()));
+                arguments.cas = cas;
+            }
+        }
+        let puts = closest.into_iter().filter_map(|(node, token)| {
+            let arguments = Arguments {
+                token: Some(token?),
+                ..arguments.clone()
+            };
+            Some(async move { self.query(node.addr, "put", arguments).await })
+        });
+        let mut stored = 0;
+        let mut failure = None;
+        for result in futures_util::future::join_all(puts).await {
+            match result {
+                Ok(_) => stored += 1,
+                Err(e) => failure = failure.or(Some(e)),
+            }
+        }
+        match failure {
+            Some(e) if stored == 0 => Err(e),
+            _ if stored == 0 => Err(DhtError::NoNodes),
+            _ => Ok(stored),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn value(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    /// The arguments of a `put` of `item`.
+    fn put_arguments(item: &MutableItem, cas: Option<i64>) -> Arguments {
+        Arguments {
+            v: Some(item.value.clone()),
+            k: Some(ByteBuf::from(item.public_key.to_vec())),
+            sig: Some(ByteBuf::from(item.signature.to_vec())),
+            seq: Some(item.seq),
+            salt: (!item.salt.is_empty()).then(|| ByteBuf::from(item.salt.clone())),
+            cas,
+            ..Arguments::default()
+        }
+    }
+
+    #[test]
+    fn signed_data_matches_bep_44() {
+        assert_eq!(signed_data(b"", 1, &value("Hello World!")), b"3:seqi1e1:v12:Hello World!");
+        assert_eq!(
+            signed_data(b"foobar", 1, &value("Hello World!")),
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!"
+        );
+    }
+
+    #[test]
+    fn signatures_cover_every_field() {
+        let item = MutableItem::sign(&key(), b"salt".to_vec(), 3, value("hello")).unwrap();
+        assert!(item.verify());
+        for tampered in [
+            MutableItem { seq: 4, ..item.clone() },
+            MutableItem { salt: b"other".to_vec(), ..item.clone() },
+            MutableItem { value: value("bye"), ..item.clone() },
+            MutableItem { public_key: SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes(), ..item.clone() },
+        ] {
+            assert!(!tampered.verify());
+        }
+    }
+
+    #[test]
+    fn stores_and_serves_immutable_items() {
+        let mut storage = Storage::default();
+        let v = value("immutable");
+        storage
+            .put(&Arguments {
+                v: Some(v.clone()),
+                ..Arguments::default()
+            })
+            .unwrap();
+        let mut values = Values::default();
+        storage.fill(&immutable_target(&v), &mut values, None);
+        assert_eq!(values.v, Some(v));
+    }
+
+    #[test]
+    fn rejects_oversized_values_and_salts() {
+        let mut storage = Storage::default();
+        let arguments = Arguments {
+            v: Some(Value::Bytes(vec![0; MAX_VALUE_LEN])),
+            ..Arguments::default()
+        };
+        assert_eq!(storage.put(&arguments).unwrap_err().0, krpc::ERROR_VALUE_TOO_BIG);
+
+        let item = MutableItem::sign(&key(), Vec::new(), 1, value("v")).unwrap();
+        let arguments = Arguments {
+            salt: Some(ByteBuf::from(vec![0; MAX_SALT_LEN + 1])),
+            ..put_arguments(&item, None)
+        };
+        assert_eq!(storage.put(&arguments).unwrap_err().0, krpc::ERROR_SALT_TOO_BIG);
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let mut storage = Storage::default();
+        let item = MutableItem::sign(&key(), Vec::new(), 1, value("v")).unwrap();
+        let arguments = Arguments {
+            seq: Some(2),
+            ..put_arguments(&item, None)
+        };
+        assert_eq!(storage.put(&arguments).unwrap_err().0, krpc::ERROR_INVALID_SIGNATURE);
+    }
+
+    #[test]
+    fn mutable_items_only_move_forward() {
+        let mut storage = Storage::default();
+        let first = MutableItem::sign(&key(), Vec::new(), 2, value("first")).unwrap();
+        storage.put(&put_arguments(&first, None)).unwrap();
+        // Storing the same item again is fine.
+        storage.put(&put_arguments(&first, None)).unwrap();
+
+        let older = MutableItem::sign(&key(), Vec::new(), 1, value("older")).unwrap();
+        assert_eq!(storage.put(&put_arguments(&older, None)).unwrap_err().0, krpc::ERROR_SEQ_TOO_LOW);
+        let same_seq = MutableItem::sign(&key(), Vec::new(), 2, value("other")).unwrap();
+        assert_eq!(storage.put(&put_arguments(&same_seq, None)).unwrap_err().0, krpc::ERROR_SEQ_TOO_LOW);
+
+        let newer = MutableItem::sign(&key(), Vec::new(), 3, value("newer")).unwrap();
+        storage.put(&put_arguments(&newer, None)).unwrap();
+        let mut values = Values::default();
+        storage.fill(&newer.target(), &mut values, None);
+        assert_eq!((values.seq, values.v), (Some(3), Some(value("newer"))));
+
+        // Nodes that are up to date only hear the sequence number.
+        let mut values = Values::default();
+        storage.fill(&newer.target(), &mut values, Some(3));
+        assert_eq!((values.seq, values.v), (Some(3), None));
+    }
+
+    #[test]
+    fn compare_and_swap_needs_the_current_seq() {
+        let mut storage = Storage::default();
+        let first = MutableItem::sign(&key(), Vec::new(), 1, value("first")).unwrap();
+        storage.put(&put_arguments(&first, None)).unwrap();
+
+        let second = MutableItem::sign(&key(), Vec::new(), 2, value("second")).unwrap();
+        assert_eq!(storage.put(&put_arguments(&second, Some(0))).unwrap_err().0, krpc::ERROR_CAS_MISMATCH);
+        storage.put(&put_arguments(&second, Some(1))).unwrap();
+    }
+
+    #[test]
+    fn evicts_the_oldest_item_when_full() {
+        let mut storage = Storage::default();
+        for i in 0..=MAX_ITEMS {
+            storage
+                .put(&Arguments {
+                    v: Some(Value::Int(i as i64)),
+                    ..Arguments::default()
+                })
+                .unwrap();
+        }
+        assert_eq!(storage.items.len(), MAX_ITEMS);
+        assert!(!storage.items.contains_key(&immutable_target(&Value::Int(0))));
+        assert!(storage.items.contains_key(&immutable_target(&Value::Int(MAX_ITEMS as i64))));
+    }
+}
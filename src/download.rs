@@ -1,17 +1,1105 @@
-use futures_util::task::waker;
+use crate::peer::{
+    generate_peer_id, Bitfield, ExtensionHandshake, Message, MessageTag, PeerConnection, Piece, Request,
+    EXTENSION_HANDSHAKE,
+};
+use crate::pex::{self, PexSession};
+use crate::picker::{PiecePicker, PieceStatus, RarestFirst};
+use crate::storage::Storage;
+use crate::torrent::{File, Keys, Torrent};
+use crate::BLOCK_MAX;
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
 
-use crate::torrent::File;
+/// Maximum number of peers we talk to at the same time.
+const MAX_PEERS: usize = 20;
 
-pub async fn all() -> anyhow::Result<Downloaded> {
+/// Number of block requests we keep in flight per peer.
+const PIPELINE_DEPTH: usize = 5;
+
+/// A peer that leaves our requests unanswered for this long is dropped.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a peer without work looks for blocks that have missed their deadline.
+const IDLE_RECHECK: Duration = Duration::from_secs(1);
+
+/// Number of failed pieces a peer may contribute blocks to before we stop talking to it.
+const BAN_STRIKES: usize = 2;
+
+/// Number of block requests from a peer we queue up before turning more away.
+const MAX_QUEUED_UPLOADS: usize = 250;
+
+/// Knobs for a single download.
+pub struct DownloadOptions {
+    /// Decides which piece each peer works on next. Defaults to [`RarestFirst`]; use
+    /// [`Sequential`](crate::picker::Sequential) to stream in order.
+    pub picker: Box<dyn PiecePicker>,
+    /// Receives a copy of every piece as soon as it is verified, e.g. to write it out while the
+    /// rest of the download is still in progress.
+    pub verified_pieces: Option<mpsc::UnboundedSender<(usize, Vec<u8>)>>,
+    /// Peers discovered while the download is running, e.g. by re-announcing to the tracker. As
+    /// long as this is open, the download waits for more peers instead of giving up when every
+    /// peer it knows of has disconnected.
+    pub peer_source: Option<mpsc::UnboundedReceiver<DiscoveredPeer>>,
+    /// Connections peers opened to us for this torrent, e.g. through a
+    /// [`Listener`](crate::listener::Listener). Like `peer_source`, keeps the download waiting
+    /// for peers as long as it is open.
+    pub incoming: Option<mpsc::UnboundedReceiver<PeerConnection>>,
+    /// Transfer totals to keep up to date, e.g. for an [`Announcer`](crate::tracker::Announcer).
+    pub progress: Option<Arc<Progress>>,
+    /// How we introduce ourselves to peers; should match what we announce to trackers.
+    pub peer_id: [u8; 20],
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            picker: Box::new(RarestFirst::new()),
+            verified_pieces: None,
+            peer_source: None,
+            incoming: None,
+            progress: None,
+            peer_id: generate_peer_id(),
+        }
+    }
+}
+
+/// A peer that turned up while a download is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+}
+
+/// Where we heard about a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    /// The announce url of the tracker that handed out the peer.
+    Tracker(String),
+    Dht,
+    /// Another peer of the download, through peer exchange.
+    Pex,
+    /// A peer on the local network that announced the torrent.
+    Lsd,
+}
+
+impl std::fmt::Display for PeerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerSource::Tracker(url) => write!(f, "tracker {url}"),
+            PeerSource::Dht => write!(f, "the dht"),
+            PeerSource::Pex => write!(f, "peer exchange"),
+            PeerSource::Lsd => write!(f, "local service discovery"),
+        }
+    }
+}
+
+/// Running byte counts of a download, as trackers want them reported.
+#[derive(Debug, Default)]
+pub struct Progress {
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    left: AtomicU64,
+    /// Woken once `left` drops to zero.
+    completed: Notify,
+}
+
+impl Progress {
+    /// `left` is the number of bytes we do not have verified yet.
+    pub fn new(left: u64) -> Self {
+        Self {
+            left: AtomicU64::new(left),
+            ..Self::default()
+        }
+    }
+
+    /// Bytes received from peers, including those of pieces that failed their hash check.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent to peers.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records that a piece of `length` bytes passed its hash check.
+    pub fn piece_verified(&self, length: u64) {
+        let before = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(length))
+            })
+            .expect("update always succeeds");
+        if before > 0 && before <= length {
+            self.completed.notify_waiters();
+        }
+    }
+
+    /// Resolves once nothing is left to download.
+    pub async fn completed(&self) {
+        // Register before checking so that a piece verified in between still wakes us.
+        let notified = self.completed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if self.left() == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Downloads every piece of `t` from the given peers.
+pub async fn all(
+    t: &Torrent,
+    peers: &[SocketAddr],
+    options: DownloadOptions,
+) -> anyhow::Result<Downloaded> {
+    let wanted = (0..t.info.pieces.0.len()).collect();
+    let (pieces, stats) = run(t, peers, wanted, None, options).await?;
+
+    let files = match &t.info.keys {
+        Keys::SingleFile { length } => vec![File {
+            length: *length,
+            path: vec![t.info.name.clone()],
+        }],
+        Keys::MultipleFile { files } => files.clone(),
+    };
+    Ok(Downloaded {
+        bytes: pieces.concat(),
+        files,
+        stats,
+    })
+}
+
+/// Downloads the single piece at `piece_i` from the given peers.
+pub async fn piece(
+    t: &Torrent,
+    peers: &[SocketAddr],
+    piece_i: usize,
+    options: DownloadOptions,
+) -> anyhow::Result<(Vec<u8>, DownloadStats)> {
+    anyhow::ensure!(
+        piece_i < t.info.pieces.0.len(),
+        "piece {piece_i} is out of range"
+    );
+    let (mut pieces, stats) = run(t, peers, vec![piece_i], None, options).await?;
+    Ok((std::mem::take(&mut pieces[piece_i]), stats))
+}
+
+/// Serves the pieces of `t` that `storage` has, as found by [`Storage::verify`], to the given
+/// peers and those that come up later, reading each block from storage as it is asked for. Only
+/// returns once there is no peer left and no way to find any.
+pub async fn seed(
+    t: &Torrent,
+    peers: &[SocketAddr],
+    storage: Arc<Storage>,
+    have: &Bitfield,
+    options: DownloadOptions,
+) -> anyhow::Result<DownloadStats> {
+    let (_, stats) = run(t, peers, Vec::new(), Some((storage, have)), options).await?;
+    Ok(stats)
+}
+
+/// What went wrong along the way of a successful download.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadStats {
+    /// Number of assembled pieces that did not match their hash and were fetched again.
+    pub hash_failures: usize,
+    /// Bytes received for pieces that failed their hash check.
+    pub wasted_bytes: usize,
+    /// Peers we disconnected from for repeatedly sending data that failed its hash check.
+    pub banned_peers: Vec<SocketAddr>,
+    /// Every source that told us about each peer discovered during the download.
+    pub peer_sources: HashMap<SocketAddr, Vec<PeerSource>>,
+    /// Bytes of blocks we sent to peers.
+    pub uploaded_bytes: usize,
+}
+
+/// Fetches the `wanted` pieces concurrently from up to [`MAX_PEERS`] peers at a time, replacing
+/// peers that disconnect with untried ones, while serving the pieces we have to them. Returns the
+/// piece data indexed by piece, with empty vectors for pieces that were not wanted. When seeding
+/// the pieces `stored` says storage has, keeps serving instead of returning once nothing is left
+/// to fetch.
+async fn run(
+    t: &Torrent,
+    peers: &[SocketAddr],
+    wanted: Vec<usize>,
+    stored: Option<(Arc<Storage>, &Bitfield)>,
+    mut options: DownloadOptions,
+) -> anyhow::Result<(Vec<Vec<u8>>, DownloadStats)> {
+    let mut peer_source = options.peer_source.take();
+    let mut incoming = options.incoming.take();
+    let (discovered, mut exchanged) = mpsc::unbounded_channel();
+    let shared = Arc::new(Shared {
+        info_hash: t.info_hash(),
+        peer_id: options.peer_id,
+        npieces: t.info.pieces.0.len(),
+        pex: !t.is_private(),
+        discovered,
+        seeding: stored.is_some(),
+        swarm: Mutex::new(Swarm::new(t, &wanted, stored, options)),
+        notify: Notify::new(),
+    });
+
+    let mut candidates = Candidates::default();
+    for &addr in peers {
+        candidates.add(addr);
+    }
+    let mut tasks = JoinSet::new();
+    let mut last_error: Option<anyhow::Error> = None;
+    while !shared.is_done() {
+        // Peers learned by peer tasks that have finished since are still worth trying.
+        while let Ok(peer) = exchanged.try_recv() {
+            candidates.discovered(peer);
+        }
+        while tasks.len() < MAX_PEERS {
+            let Some(addr) = candidates.untried.pop_front() else {
+                break;
+            };
+            tasks.spawn(connect(addr, Arc::clone(&shared)));
+        }
+
+        tokio::select! {
+            Some(joined) = tasks.join_next() => match joined {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => last_error = Some(e),
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(_) => {}
+            },
+            discovered = recv_peer(&mut peer_source), if peer_source.is_some() => match discovered {
+                Some(peer) => candidates.discovered(peer),
+                None => peer_source = None,
+            },
+            accepted = recv_peer(&mut incoming), if incoming.is_some() => match accepted {
+                // Peers that come to us do not take the place of those we want to connect to.
+                Some(peer) if tasks.len() < MAX_PEERS && !shared.lock().is_banned(peer.addr()) => {
+                    tasks.spawn(participate(peer, None, Arc::clone(&shared)));
+                }
+                Some(_) => {}
+                None => incoming = None,
+            },
+            // Only peer tasks send here, so without any there is nothing to wait for.
+            Some(peer) = exchanged.recv(), if !tasks.is_empty() => candidates.discovered(peer),
+            else => {
+                const GAVE_UP: &str = "all peers disconnected before the download finished";
+                if shared.seeding {
+                    return Err(anyhow::anyhow!("no peers are left to seed to"));
+                }
+                return Err(match last_error {
+                    Some(last) => last.context(GAVE_UP),
+                    None => anyhow::anyhow!(GAVE_UP),
+                });
+            }
+        }
+    }
+    tasks.abort_all();
+
+    let mut swarm = shared.lock();
+    let pieces = swarm
+        .pieces
+        .iter_mut()
+        .map(|piece| match std::mem::replace(piece, PieceState::Skipped) {
+            PieceState::Complete(data) => data,
+            _ => Vec::new(),
+        })
+        .collect();
+    let mut stats = std::mem::take(&mut swarm.stats);
+    stats.peer_sources = candidates.sources;
+    Ok((pieces, stats))
+}
+
+/// The peers a download may connect to. Every address is tried at most once, however often it
+/// is rediscovered.
+#[derive(Debug, Default)]
+struct Candidates {
+    known: HashSet<SocketAddr>,
+    untried: VecDeque<SocketAddr>,
+    /// Every source that told us about each discovered peer.
+    sources: HashMap<SocketAddr, Vec<PeerSource>>,
+}
+
+impl Candidates {
+    fn add(&mut self, addr: SocketAddr) {
+        if self.known.insert(addr) {
+            self.untried.push_back(addr);
+        }
+    }
+
+    fn discovered(&mut self, DiscoveredPeer { addr, source }: DiscoveredPeer) {
+        self.add(addr);
+        let sources = self.sources.entry(addr).or_default();
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+}
+
+async fn recv_peer<T>(source: &mut Option<mpsc::UnboundedReceiver<T>>) -> Option<T> {
+    source.as_mut()?.recv().await
+}
+
+async fn connect(addr: SocketAddr, shared: Arc<Shared>) -> anyhow::Result<()> {
+    anyhow::ensure!(!shared.lock().is_banned(addr), "peer is banned");
+    let peer = PeerConnection::connect(addr, shared.info_hash, shared.peer_id).await?;
+    // We got through to the peer, so others can too.
+    participate(peer, Some(pex::REACHABLE), shared).await
+}
+
+/// Drives a single peer: requests blocks of pieces it has until the download is done or the peer
+/// misbehaves. Blocks still in flight when this returns are handed back for other peers.
+/// Peer exchange tells others about the peer with `pex_flags`, unless there are none because the
+/// peer connected to us and we do not know where it listens.
+async fn participate(mut peer: PeerConnection, pex_flags: Option<u8>, shared: Arc<Shared>) -> anyhow::Result<()> {
+    let addr = peer.addr();
+    let mut bitfield = Bitfield::new(shared.npieces);
+    let mut inflight = Vec::new();
+    if let Some(flags) = pex_flags {
+        shared.lock().connected.insert(addr, flags);
+    }
+    let result = exchange(&mut peer, &shared, &mut bitfield, &mut inflight).await;
+    {
+        let mut swarm = shared.lock();
+        swarm.connected.remove(&addr);
+        swarm.release(addr, &inflight);
+        swarm.picker.peer_removed(&bitfield);
+    }
+    shared.notify.notify_waiters();
+    result
+}
+
+async fn exchange(
+    peer: &mut PeerConnection,
+    shared: &Shared,
+    bitfield: &mut Bitfield,
+    inflight: &mut Vec<BlockRequest>,
+) -> anyhow::Result<()> {
+    let addr = peer.addr();
+    let mut choked = true;
+    let mut interested = false;
+    // When the peer last delivered a block, or when we started waiting on it.
+    let mut last_progress = Instant::now();
+    let mut npieces_had = 0;
+    let mut pex = PexSession::default();
+    if shared.pex && peer.supports_extensions() {
+        let handshake = ExtensionHandshake {
+            m: [(pex::EXTENSION_NAME.to_string(), i64::from(pex::LOCAL_ID))].into(),
+            ..Default::default()
+        };
+        let handshake = serde_bencode::to_bytes(&handshake).context("encode extension handshake")?;
+        peer.send_extended(EXTENSION_HANDSHAKE, &handshake).await?;
+    }
+
+    // We start out choking the peer and unchoke it as soon as it is interested.
+    let mut choking = true;
+    let mut uploads: VecDeque<BlockRequest> = VecDeque::new();
+    // Pieces verified from here on are announced with `Have` as they come in.
+    let (have, mut announced) = {
+        let swarm = shared.lock();
+        (swarm.bitfield(), swarm.verified.len())
+    };
+    let npieces_have = have.pieces().count();
+    let tag = match npieces_have {
+        n if n == shared.npieces && peer.supports_fast() => Some(MessageTag::HaveAll),
+        0 if peer.supports_fast() => Some(MessageTag::HaveNone),
+        // Without any pieces the bitfield may be left out.
+        0 => None,
+        _ => Some(MessageTag::Bitfield),
+    };
+    if let Some(tag) = tag {
+        let payload = if tag == MessageTag::Bitfield {
+            have.as_bytes().to_vec()
+        } else {
+            Vec::new()
+        };
+        peer.send(Message { tag, payload }).await?;
+    }
+
+    loop {
+        // Subscribe before looking for work so that blocks released in the meantime wake us.
+        let notified = shared.notify.notified();
+        if shared.is_done() {
+            return Ok(());
+        }
+
+        let verified: Vec<usize> = shared.lock().verified[announced..].to_vec();
+        announced += verified.len();
+        for piece_i in verified {
+            peer.send(Message {
+                tag: MessageTag::Have,
+                payload: (piece_i as u32).to_be_bytes().to_vec(),
+            })
+            .await?;
+        }
+
+        anyhow::ensure!(
+            !shared.lock().is_banned(addr),
+            "peer sent data for {BAN_STRIKES} pieces that failed their hash check"
+        );
+        // Two seeds have nothing to say to each other.
+        if shared.lock().remaining == 0 && bitfield.pieces().count() == shared.npieces {
+            return Ok(());
+        }
+
+        // One block at a time, so that cancels that arrive in the meantime still count.
+        if let Some(block) = uploads.pop_front() {
+            // Queued blocks are of pieces we have, but a finished download hands its pieces out
+            // before the peer tasks it aborted stop, and those find nothing left to send.
+            let source = shared.lock().block(&block);
+            let data = match source {
+                Some(BlockData::Memory(data)) => data,
+                Some(BlockData::Stored(storage, offset)) => storage
+                    .read(offset, block.length)
+                    .await
+                    .with_context(|| format!("read block of piece {}", block.piece_i))?,
+                None => continue,
+            };
+            let mut payload = Vec::with_capacity(8 + data.len());
+            payload.extend_from_slice(&(block.piece_i as u32).to_be_bytes());
+            payload.extend_from_slice(&(block.begin as u32).to_be_bytes());
+            payload.extend_from_slice(&data);
+            peer.send(Message {
+                tag: MessageTag::Piece,
+                payload,
+            })
+            .await
+            .with_context(|| format!("send block of piece {}", block.piece_i))?;
+            shared.lock().block_sent(data.len());
+        }
+
+        // In endgame the same block is requested from several peers; once one of them delivers
+        // it, the others are told not to bother.
+        let stale: Vec<_> = {
+            let swarm = shared.lock();
+            inflight
+                .iter()
+                .filter(|block| !swarm.is_requested_by(addr, block))
+                .copied()
+                .collect()
+        };
+        inflight.retain(|block| !stale.contains(block));
+        for block in stale {
+            peer.send(block.message(MessageTag::Cancel))
+                .await
+                .with_context(|| format!("send cancel for piece {}", block.piece_i))?;
+        }
+
+        if !interested && shared.lock().wants_any(bitfield) {
+            peer.send(Message {
+                tag: MessageTag::Interested,
+                payload: Vec::new(),
+            })
+            .await?;
+            interested = true;
+        }
+
+        while !choked && inflight.len() < PIPELINE_DEPTH {
+            let Some(block) = shared.lock().next_block(bitfield, addr) else {
+                break;
+            };
+            if inflight.is_empty() {
+                last_progress = Instant::now();
+            }
+            inflight.push(block);
+            peer.send(block.message(MessageTag::Request))
+                .await
+                .with_context(|| format!("send request for piece {}", block.piece_i))?;
+        }
+
+        let pex_due = pex.next_send();
+        let message = tokio::select! {
+            message = peer.recv() => message?,
+            _ = notified => continue,
+            _ = std::future::ready(()), if !uploads.is_empty() => continue,
+            _ = tokio::time::sleep_until(pex_due.unwrap_or_else(Instant::now).into()), if pex_due.is_some() => {
+                let update = pex.update(addr, &shared.lock().connected);
+                if let Some((id, message)) = update {
+                    let message = serde_bencode::to_bytes(&message).context("encode peer exchange message")?;
+                    peer.send_extended(id, &message).await?;
+                }
+                continue;
+            }
+            _ = tokio::time::sleep(IDLE_RECHECK), if inflight.is_empty() && !choked => continue,
+            _ = tokio::time::sleep_until((last_progress + PEER_TIMEOUT).into()), if !inflight.is_empty() => {
+                anyhow::bail!("peer did not answer our requests in time");
+            }
+        };
+
+        match message.tag {
+            MessageTag::Choke => {
+                choked = true;
+                // A choking peer discards all of our pending requests.
+                shared.lock().release(addr, inflight);
+                inflight.clear();
+                shared.notify.notify_waiters();
+            }
+            MessageTag::Unchoke => choked = false,
+            MessageTag::Bitfield | MessageTag::HaveAll | MessageTag::HaveNone => {
+                let new = match message.tag {
+                    MessageTag::HaveAll => Bitfield::full(shared.npieces),
+                    MessageTag::HaveNone => Bitfield::new(shared.npieces),
+                    _ => Bitfield::from_payload(message.payload, shared.npieces),
+                };
+                npieces_had = new.pieces().count();
+                let mut swarm = shared.lock();
+                swarm.picker.peer_removed(bitfield);
+                swarm.picker.peer_added(&new);
+                *bitfield = new;
+                swarm.peer_has(addr, npieces_had);
+            }
+            MessageTag::Have => {
+                let index: [u8; 4] = message
+                    .payload
+                    .as_slice()
+                    .try_into()
+                    .context("have message must carry a 4 byte index")?;
+                let piece_i = u32::from_be_bytes(index) as usize;
+                if piece_i < shared.npieces && !bitfield.has_piece(piece_i) {
+                    bitfield.set_piece(piece_i);
+                    npieces_had += 1;
+                    let mut swarm = shared.lock();
+                    swarm.picker.peer_has(piece_i);
+                    swarm.peer_has(addr, npieces_had);
+                }
+            }
+            MessageTag::Piece => {
+                let piece = Piece::ref_from_bytes(&message.payload)
+                    .context("piece message is too short")?;
+                let (piece_i, begin) = (piece.index() as usize, piece.begin() as usize);
+                let Some(at) = inflight
+                    .iter()
+                    .position(|b| b.piece_i == piece_i && b.begin == begin && b.length == piece.block().len())
+                else {
+                    // Late answer to a request we already gave up on.
+                    continue;
+                };
+                let block = inflight.swap_remove(at);
+                last_progress = Instant::now();
+                let outcome = shared.lock().block_received(addr, &block, piece.block());
+                match outcome {
+                    BlockOutcome::Stored { duplicated: false } => {}
+                    BlockOutcome::Stored { duplicated: true }
+                    | BlockOutcome::PieceVerified
+                    | BlockOutcome::HashMismatch => shared.notify.notify_waiters(),
+                }
+            }
+            MessageTag::Reject => {
+                let block = BlockRequest::from_payload(&message.payload)
+                    .context("reject message must carry a 12 byte request")?;
+                if let Some(at) = inflight.iter().position(|b| *b == block) {
+                    inflight.swap_remove(at);
+                    shared.lock().release(addr, &[block]);
+                    shared.notify.notify_waiters();
+                }
+            }
+            MessageTag::Interested if choking => {
+                peer.send(Message {
+                    tag: MessageTag::Unchoke,
+                    payload: Vec::new(),
+                })
+                .await?;
+                choking = false;
+            }
+            MessageTag::Request => {
+                let block = BlockRequest::from_payload(&message.payload)
+                    .context("request message must carry 12 bytes")?;
+                anyhow::ensure!(
+                    block.length > 0 && block.length <= BLOCK_MAX,
+                    "peer requested a block of {} bytes",
+                    block.length
+                );
+                let available = shared.lock().has_block(&block);
+                if !choking && available && uploads.len() < MAX_QUEUED_UPLOADS {
+                    uploads.push_back(block);
+                } else if peer.supports_fast() {
+                    peer.send(block.message(MessageTag::Reject)).await?;
+                }
+            }
+            MessageTag::Cancel => {
+                let block = BlockRequest::from_payload(&message.payload)
+                    .context("cancel message must carry 12 bytes")?;
+                if let Some(at) = uploads.iter().position(|b| *b == block) {
+                    uploads.remove(at);
+                    // With the fast extension, every request gets an answer.
+                    if peer.supports_fast() {
+                        peer.send(block.message(MessageTag::Reject)).await?;
+                    }
+                }
+            }
+            MessageTag::Extended if shared.pex => {
+                let (id, payload) = message.extended()?;
+                if id == EXTENSION_HANDSHAKE {
+                    let handshake: ExtensionHandshake =
+                        serde_bencode::from_bytes(payload).context("parse extension handshake")?;
+                    pex.handshake(&handshake);
+                } else if id == pex::LOCAL_ID {
+                    for (addr, _) in pex.receive(payload)? {
+                        // The download is over once nobody listens anymore.
+                        let _ = shared.discovered.send(DiscoveredPeer {
+                            addr,
+                            source: PeerSource::Pex,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// State shared between the peer tasks of a single download.
+struct Shared {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    npieces: usize,
+    /// Whether peers may tell each other about the peers of this download.
+    pex: bool,
+    /// Peers that peers told us about.
+    discovered: mpsc::UnboundedSender<DiscoveredPeer>,
+    /// Whether to keep serving pieces once we have all of them.
+    seeding: bool,
+    swarm: Mutex<Swarm>,
+    /// Woken whenever blocks become available again or the download finishes.
+    notify: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Swarm> {
+        self.swarm.lock().expect("swarm lock poisoned")
+    }
+
+    fn is_done(&self) -> bool {
+        !self.seeding && self.lock().remaining == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockRequest {
+    piece_i: usize,
+    begin: usize,
+    length: usize,
+}
+
+impl BlockRequest {
+    fn from_payload(payload: &[u8]) -> Option<Self> {
+        let request = Request::from_payload(payload)?;
+        Some(Self {
+            piece_i: request.index() as usize,
+            begin: request.begin() as usize,
+            length: request.length() as usize,
+        })
+    }
+
+    /// A `Request`, `Cancel` or `Reject` message for the block.
+    fn message(&self, tag: MessageTag) -> Message {
+        let mut request = Request::new(self.piece_i as u32, self.begin as u32, self.length as u32);
+        Message {
+            tag,
+            payload: Vec::from(request.as_bytes_mut()),
+        }
+    }
+}
+
+enum BlockOutcome {
+    /// `duplicated` is set when other peers were asked for the same block and should cancel.
+    Stored { duplicated: bool },
+    PieceVerified,
+    HashMismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    /// In flight to every peer in `by`; `since` is when it was last handed out.
+    Requested { by: Vec<SocketAddr>, since: Instant },
+    Received { from: SocketAddr },
+}
+
+enum PieceState {
+    Skipped,
+    Missing,
+    Pending { data: Vec<u8>, blocks: Vec<BlockState> },
+    Complete(Vec<u8>),
+    /// Verified and kept in storage rather than memory.
+    Stored,
+}
+
+/// Where the data of a block we serve comes from.
+enum BlockData {
+    Memory(Vec<u8>),
+    /// At this offset into the torrent in storage.
+    Stored(Arc<Storage>, usize),
+}
+
+/// Block-level bookkeeping of which parts of which pieces are missing, requested or verified.
+struct Swarm {
+    pieces: Vec<PieceState>,
+    /// Summary of `pieces` handed to the picker, kept in sync by [`Swarm::refresh`].
+    status: Vec<PieceStatus>,
+    picker: Box<dyn PiecePicker>,
+    verified_pieces: Option<mpsc::UnboundedSender<(usize, Vec<u8>)>>,
+    progress: Option<Arc<Progress>>,
+    hashes: Vec<[u8; 20]>,
+    piece_length: usize,
+    lengths: Vec<usize>,
+    remaining: usize,
+    /// Failed pieces each peer contributed blocks to; we try to get those from someone else.
+    implicated: HashMap<SocketAddr, HashSet<usize>>,
+    banned: HashSet<SocketAddr>,
+    /// The peers we are talking to, with their flags for peer exchange.
+    connected: HashMap<SocketAddr, u8>,
+    /// Pieces in the order they were verified during the download, for peers to announce.
+    verified: Vec<usize>,
+    /// Where the [`PieceState::Stored`] pieces are.
+    storage: Option<Arc<Storage>>,
+    stats: DownloadStats,
+}
+
+impl Swarm {
+    fn new(
+        t: &Torrent,
+        wanted: &[usize],
+        stored: Option<(Arc<Storage>, &Bitfield)>,
+        options: DownloadOptions,
+    ) -> Self {
+        let npieces = t.info.pieces.0.len();
+        let mut pieces: Vec<_> = (0..npieces).map(|_| PieceState::Skipped).collect();
+        for &piece_i in wanted {
+            pieces[piece_i] = PieceState::Missing;
+        }
+        let storage = stored.map(|(storage, have)| {
+            for piece_i in have.pieces() {
+                pieces[piece_i] = PieceState::Stored;
+            }
+            storage
+        });
+        let mut swarm = Self {
+            remaining: 0,
+            status: vec![PieceStatus::Unwanted; npieces],
+            pieces,
+            picker: options.picker,
+            verified_pieces: options.verified_pieces,
+            progress: options.progress,
+            hashes: t.info.pieces.0.clone(),
+            piece_length: t.info.piece_length,
+            lengths: (0..npieces).map(|piece_i| t.piece_length(piece_i)).collect(),
+            implicated: HashMap::new(),
+            banned: HashSet::new(),
+            connected: HashMap::new(),
+            verified: Vec::new(),
+            storage,
+            stats: DownloadStats::default(),
+        };
+        for piece_i in 0..npieces {
+            swarm.refresh(piece_i);
+        }
+        swarm.remaining = swarm
+            .status
+            .iter()
+            .filter(|&&status| status == PieceStatus::Missing)
+            .count();
+        swarm
+    }
+
+    fn refresh(&mut self, piece_i: usize) {
+        self.status[piece_i] = match &self.pieces[piece_i] {
+            PieceState::Skipped => PieceStatus::Unwanted,
+            PieceState::Missing => PieceStatus::Missing,
+            PieceState::Pending { blocks, .. } if blocks.contains(&BlockState::Open) => {
+                PieceStatus::Partial
+            }
+            PieceState::Pending { .. } => PieceStatus::Requested,
+            PieceState::Complete(_) | PieceState::Stored => PieceStatus::Complete,
+        };
+    }
+
+    /// The pieces we have.
+    fn bitfield(&self) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.pieces.len());
+        for (piece_i, piece) in self.pieces.iter().enumerate() {
+            if let PieceState::Complete(_) | PieceState::Stored = piece {
+                bitfield.set_piece(piece_i);
+            }
+        }
+        bitfield
+    }
+
+    /// Whether we have the piece of a block a peer asked for, and the block lies within it.
+    fn has_block(&self, block: &BlockRequest) -> bool {
+        let have = matches!(
+            self.pieces.get(block.piece_i),
+            Some(PieceState::Complete(_) | PieceState::Stored)
+        );
+        have && block
+            .begin
+            .checked_add(block.length)
+            .is_some_and(|end| end <= self.lengths[block.piece_i])
+    }
+
+    /// Where to get the data of a block a peer asked for, if we have it.
+    fn block(&self, block: &BlockRequest) -> Option<BlockData> {
+        if !self.has_block(block) {
+            return None;
+        }
+        let range = block.begin..block.begin + block.length;
+        match &self.pieces[block.piece_i] {
+            PieceState::Complete(data) => Some(BlockData::Memory(data[range].to_vec())),
+            PieceState::Stored => {
+                let offset = block.piece_i * self.piece_length + block.begin;
+                Some(BlockData::Stored(Arc::clone(self.storage.as_ref()?), offset))
+            }
+            _ => None,
+        }
+    }
+
+    fn block_sent(&mut self, length: usize) {
+        self.stats.uploaded_bytes += length;
+        if let Some(progress) = &self.progress {
+            progress.add_uploaded(length as u64);
+        }
+    }
+
+    /// Records that the peer at `addr` has `npieces_had` pieces, marking it a seed if that is all.
+    fn peer_has(&mut self, addr: SocketAddr, npieces_had: usize) {
+        if npieces_had == self.pieces.len() {
+            if let Some(flags) = self.connected.get_mut(&addr) {
+                *flags |= pex::SEED;
+            }
+        }
+    }
+
+    fn wants_any(&self, bitfield: &Bitfield) -> bool {
+        bitfield.pieces().any(|piece_i| {
+            matches!(
+                self.status[piece_i],
+                PieceStatus::Missing | PieceStatus::Partial | PieceStatus::Requested
+            )
+        })
+    }
+
+    /// Hands out a block to the peer with the given bitfield. Requests that missed the picker's
+    /// deadline come first, so that a stalled peer does not hold up the pieces the picker is in a
+    /// hurry for; otherwise the picker chooses a piece and the peer gets one of its open blocks.
+    fn next_block(&mut self, bitfield: &Bitfield, addr: SocketAddr) -> Option<BlockRequest> {
+        if let Some(request) = self.duplicate_block(bitfield, addr) {
+            return Some(request);
+        }
+        let picked = match self.implicated.get(&addr) {
+            Some(failed) => {
+                let mut preferred = bitfield.clone();
+                for &piece_i in failed {
+                    preferred.clear_piece(piece_i);
+                }
+                self.picker
+                    .pick(&preferred, &self.status)
+                    .or_else(|| self.picker.pick(bitfield, &self.status))
+            }
+            None => self.picker.pick(bitfield, &self.status),
+        };
+        if let Some(piece_i) = picked {
+            let length = self.lengths[piece_i];
+            if let PieceState::Missing = self.pieces[piece_i] {
+                self.pieces[piece_i] = PieceState::Pending {
+                    data: vec![0; length],
+                    blocks: vec![BlockState::Open; length.div_ceil(BLOCK_MAX)],
+                };
+            }
+            if let PieceState::Pending { blocks, .. } = &mut self.pieces[piece_i] {
+                if let Some(block_i) = blocks.iter().position(|b| *b == BlockState::Open) {
+                    blocks[block_i] = BlockState::Requested {
+                        by: vec![addr],
+                        since: Instant::now(),
+                    };
+                    self.refresh(piece_i);
+                    return Some(self.block_request(piece_i, block_i));
+                }
+            }
+        }
+        None
+    }
+
+    /// Once every remaining block is in flight, the download is only as fast as the slowest peer
+    /// holding one of them, so all peers race for the last blocks.
+    fn in_endgame(&self) -> bool {
+        !self
+            .status
+            .iter()
+            .any(|status| matches!(status, PieceStatus::Missing | PieceStatus::Partial))
+    }
+
+    /// Finds a block of a piece `addr` has that has been in flight for longer than the picker's
+    /// deadline for it, or for any time at all in endgame, and adds `addr` to its requesters.
+    fn duplicate_block(&mut self, bitfield: &Bitfield, addr: SocketAddr) -> Option<BlockRequest> {
+        let endgame = self.in_endgame();
+        for piece_i in bitfield.pieces() {
+            if !matches!(self.status[piece_i], PieceStatus::Partial | PieceStatus::Requested) {
+                continue;
+            }
+            let deadline = if endgame {
+                Duration::ZERO
+            } else {
+                let Some(deadline) = self.picker.deadline(piece_i, &self.status) else {
+                    continue;
+                };
+                deadline
+            };
+            let PieceState::Pending { blocks, .. } = &mut self.pieces[piece_i] else {
+                continue;
+            };
+            let overdue = blocks.iter_mut().enumerate().find_map(|(block_i, block)| match block {
+                BlockState::Requested { by, since } if since.elapsed() >= deadline && !by.contains(&addr) => {
+                    by.push(addr);
+                    *since = Instant::now();
+                    Some(block_i)
+                }
+                _ => None,
+            });
+            if let Some(block_i) = overdue {
+                return Some(self.block_request(piece_i, block_i));
+            }
+        }
+        None
+    }
+
+    fn block_request(&self, piece_i: usize, block_i: usize) -> BlockRequest {
+        let begin = block_i * BLOCK_MAX;
+        BlockRequest {
+            piece_i,
+            begin,
+            length: BLOCK_MAX.min(self.lengths[piece_i] - begin),
+        }
+    }
+
+    fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.banned.contains(&addr)
+    }
+
+    fn is_requested_by(&self, addr: SocketAddr, request: &BlockRequest) -> bool {
+        match &self.pieces[request.piece_i] {
+            PieceState::Pending { blocks, .. } => matches!(
+                &blocks[request.begin / BLOCK_MAX],
+                BlockState::Requested { by, .. } if by.contains(&addr)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Returns blocks that `addr` will no longer deliver to the pool of open blocks.
+    fn release(&mut self, addr: SocketAddr, requests: &[BlockRequest]) {
+        for request in requests {
+            if let PieceState::Pending { blocks, .. } = &mut self.pieces[request.piece_i] {
+                let block = &mut blocks[request.begin / BLOCK_MAX];
+                if let BlockState::Requested { by, .. } = block {
+                    by.retain(|&requester| requester != addr);
+                    if by.is_empty() {
+                        *block = BlockState::Open;
+                    }
+                }
+            }
+            self.refresh(request.piece_i);
+        }
+    }
+
+    fn block_received(&mut self, addr: SocketAddr, request: &BlockRequest, block: &[u8]) -> BlockOutcome {
+        let piece_i = request.piece_i;
+        let PieceState::Pending { data, blocks } = &mut self.pieces[piece_i] else {
+            return BlockOutcome::Stored { duplicated: false };
+        };
+        let state = &mut blocks[request.begin / BLOCK_MAX];
+        let duplicated = match state {
+            BlockState::Requested { by, .. } if by.contains(&addr) => by.len() > 1,
+            _ => return BlockOutcome::Stored { duplicated: false },
+        };
+        *state = BlockState::Received { from: addr };
+        data[request.begin..request.begin + block.len()].copy_from_slice(block);
+        if let Some(progress) = &self.progress {
+            progress.add_downloaded(block.len() as u64);
+        }
+        if blocks.iter().any(|b| !matches!(b, BlockState::Received { .. })) {
+            return BlockOutcome::Stored { duplicated };
+        }
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let hash: [u8; 20] = hasher
+            .finalize()
+            .into();
+        if hash != self.hashes[piece_i] {
+            let contributors: HashSet<_> = blocks
+                .iter()
+                .filter_map(|b| match b {
+                    BlockState::Received { from } => Some(*from),
+                    _ => None,
+                })
+                .collect();
+            for contributor in contributors {
+                let failed = self.implicated.entry(contributor).or_default();
+                failed.insert(piece_i);
+                if failed.len() >= BAN_STRIKES && self.banned.insert(contributor) {
+                    self.stats.banned_peers.push(contributor);
+                }
+            }
+            self.stats.hash_failures += 1;
+            self.stats.wasted_bytes += self.lengths[piece_i];
+            self.pieces[piece_i] = PieceState::Missing;
+            self.refresh(piece_i);
+            return BlockOutcome::HashMismatch;
+        }
+
+        let PieceState::Pending { data, .. } = std::mem::replace(&mut self.pieces[piece_i], PieceState::Skipped) else {
+            unreachable!("piece is pending");
+        };
+        if let Some(verified_pieces) = &self.verified_pieces {
+            // The receiver going away only means nobody is interested in progress any more.
+            let _ = verified_pieces.send((piece_i, data.clone()));
+        }
+        if let Some(progress) = &self.progress {
+            progress.piece_verified(self.lengths[piece_i] as u64);
+        }
+        self.pieces[piece_i] = PieceState::Complete(data);
+        self.refresh(piece_i);
+        self.remaining -= 1;
+        self.verified.push(piece_i);
+        BlockOutcome::PieceVerified
+    }
 }
 
 pub struct Downloaded {
     bytes: Vec<u8>,
-    files: Vec<File>
+    files: Vec<File>,
+    stats: DownloadStats,
+}
+
+impl Downloaded {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn stats(&self) -> &DownloadStats {
+        &self.stats
+    }
 }
 
 impl<'a> IntoIterator for &'a Downloaded {
-    type Item = &'a DownloadedFile;
+    type Item = DownloadedFile<'a>;
     type IntoIter = DownloadedIter<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
@@ -36,9 +1124,231 @@ impl<'d> DownloadedIter<'d> {
 }
 
 impl<'d> Iterator for DownloadedIter<'d> {
-    type Item = &'d File;
+    type Item = DownloadedFile<'d>;
     fn next(&mut self) -> Option<Self::Item> {
         let file = self.file_iter.next()?;
-        let bytes = &se
+        let bytes = &self.downloaded.bytes[self.offset..self.offset + file.length];
+        self.offset += file.length;
+        Some(DownloadedFile { file, bytes })
+    }
+}
+
+pub struct DownloadedFile<'d> {
+    file: &'d File,
+    bytes: &'d [u8],
+}
+
+impl<'d> DownloadedFile<'d> {
+    pub fn path(&self) -> &'d [String] {
+        &self.file.path
+    }
+
+    pub fn bytes(&self) -> &'d [u8] {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picker::Sequential;
+    use crate::torrent::{Hashes, Info};
+
+    /// Pieces of two blocks each, the last one only a single block long.
+    const PIECE_LENGTH: usize = 2 * BLOCK_MAX;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// The contents of piece `piece_i` of a torrent of `npieces`.
+    fn piece_data(piece_i: usize, npieces: usize) -> Vec<u8> {
+        let length = if piece_i == npieces - 1 { BLOCK_MAX } else { PIECE_LENGTH };
+        vec![piece_i as u8; length]
+    }
+
+    fn torrent(npieces: usize) -> Torrent {
+        let hashes = (0..npieces)
+            .map(|piece_i| Sha1::digest(piece_data(piece_i, npieces)).into())
+            .collect();
+        let info = Info {
+            name: "test".to_string(),
+            piece_length: PIECE_LENGTH,
+            pieces: Hashes(hashes),
+            private: None,
+            keys: Keys::SingleFile {
+                length: (npieces - 1) * PIECE_LENGTH + BLOCK_MAX,
+            },
+        };
+        Torrent::from_metadata(info, [0; 20])
+    }
+
+    fn swarm(npieces: usize, picker: Box<dyn PiecePicker>) -> Swarm {
+        let wanted: Vec<_> = (0..npieces).collect();
+        let options = DownloadOptions {
+            picker,
+            ..DownloadOptions::default()
+        };
+        Swarm::new(&torrent(npieces), &wanted, None, options)
+    }
+
+    fn only(npieces: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(npieces);
+        for &piece_i in pieces {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
+
+    /// Hands the block of `request` to the swarm as if `from` sent it, with garbage if `corrupt`.
+    fn deliver(swarm: &mut Swarm, from: SocketAddr, request: &BlockRequest, corrupt: bool) -> BlockOutcome {
+        let data = piece_data(request.piece_i, swarm.pieces.len());
+        let mut block = data[request.begin..request.begin + request.length].to_vec();
+        if corrupt {
+            block.fill(0xff);
+        }
+        swarm.block_received(from, request, &block)
+    }
+
+    #[test]
+    fn pieces_that_fail_their_hash_are_downloaded_again() {
+        let npieces = 3;
+        let mut swarm = swarm(npieces, Box::new(RarestFirst::new()));
+        let piece_0 = only(npieces, &[0]);
+        let first = swarm.next_block(&piece_0, addr(1)).unwrap();
+        let second = swarm.next_block(&piece_0, addr(2)).unwrap();
+        assert!(matches!(deliver(&mut swarm, addr(1), &first, true), BlockOutcome::Stored { .. }));
+        assert!(matches!(deliver(&mut swarm, addr(2), &second, false), BlockOutcome::HashMismatch));
+
+        assert!(matches!(swarm.pieces[0], PieceState::Missing));
+        assert_eq!(swarm.status[0], PieceStatus::Missing);
+        assert_eq!(swarm.remaining, npieces);
+        assert_eq!(swarm.stats.hash_failures, 1);
+        assert_eq!(swarm.stats.wasted_bytes, PIECE_LENGTH);
+        // We cannot tell which of the two sent the bad block, so both are suspects.
+        for peer in [addr(1), addr(2)] {
+            assert_eq!(swarm.implicated[&peer], HashSet::from([0]));
+            assert!(!swarm.is_banned(peer));
+        }
+        // Suspects get other pieces first, and the failed one only if there is nothing else.
+        let all = Bitfield::full(npieces);
+        assert_ne!(swarm.next_block(&all, addr(1)).unwrap().piece_i, 0);
+        assert_eq!(swarm.next_block(&piece_0, addr(1)).unwrap().piece_i, 0);
+    }
+
+    #[test]
+    fn peers_are_banned_after_repeated_hash_failures() {
+        let npieces = 3;
+        let mut swarm = swarm(npieces, Box::new(RarestFirst::new()));
+        for piece_i in 0..BAN_STRIKES {
+            let bitfield = only(npieces, &[piece_i]);
+            while let Some(request) = swarm.next_block(&bitfield, addr(1)) {
+                if let BlockOutcome::HashMismatch = deliver(&mut swarm, addr(1), &request, true) {
+                    break;
+                }
+            }
+            assert_eq!(swarm.is_banned(addr(1)), piece_i + 1 == BAN_STRIKES);
+        }
+        assert_eq!(swarm.stats.hash_failures, BAN_STRIKES);
+        assert_eq!(swarm.stats.banned_peers, [addr(1)]);
+    }
+
+    #[test]
+    fn endgame_duplicates_requests_and_cancels_them() {
+        let npieces = 2;
+        let mut swarm = swarm(npieces, Box::new(RarestFirst::new()));
+        let all = Bitfield::full(npieces);
+        let mut requests: Vec<_> = std::iter::from_fn(|| swarm.next_block(&all, addr(1))).collect();
+        assert_eq!(requests.len(), 3);
+        // Duplicates are handed out in piece order.
+        requests.sort_by_key(|request| (request.piece_i, request.begin));
+        assert!(swarm.in_endgame());
+
+        // Every block is in flight, so the next peer races the first one for them.
+        let duplicate = swarm.next_block(&all, addr(2)).unwrap();
+        assert_eq!(duplicate, requests[0]);
+        assert!(swarm.is_requested_by(addr(1), &duplicate));
+        assert!(swarm.is_requested_by(addr(2), &duplicate));
+        assert!(matches!(
+            deliver(&mut swarm, addr(2), &duplicate, false),
+            BlockOutcome::Stored { duplicated: true }
+        ));
+        // The first peer is told to cancel, and a copy that arrives anyway changes nothing.
+        assert!(!swarm.is_requested_by(addr(1), &duplicate));
+        assert!(matches!(
+            deliver(&mut swarm, addr(1), &duplicate, true),
+            BlockOutcome::Stored { duplicated: false }
+        ));
+
+        // A peer that goes away leaves the block to the others that still have it in flight.
+        let duplicate = swarm.next_block(&all, addr(2)).unwrap();
+        assert_eq!(duplicate, requests[1]);
+        swarm.release(addr(2), &[duplicate]);
+        assert!(swarm.is_requested_by(addr(1), &duplicate));
+        assert!(swarm.in_endgame());
+        assert!(matches!(
+            deliver(&mut swarm, addr(1), &duplicate, false),
+            BlockOutcome::PieceVerified
+        ));
+    }
+
+    #[test]
+    fn serves_blocks_of_the_pieces_we_have() {
+        let npieces = 3;
+        let t = torrent(npieces);
+        let storage = Arc::new(Storage::new(&t, std::path::Path::new("test")));
+        let stored = only(npieces, &[0]);
+        let options = DownloadOptions::default();
+        let mut swarm = Swarm::new(&t, &[1, 2], Some((Arc::clone(&storage), &stored)), options);
+        let piece_1 = only(npieces, &[1]);
+        while let Some(request) = swarm.next_block(&piece_1, addr(1)) {
+            deliver(&mut swarm, addr(1), &request, false);
+        }
+        assert_eq!(swarm.bitfield().pieces().collect::<Vec<_>>(), [0, 1]);
+
+        let request = |piece_i, begin, length| BlockRequest { piece_i, begin, length };
+        match swarm.block(&request(0, BLOCK_MAX, BLOCK_MAX)) {
+            Some(BlockData::Stored(from, offset)) => {
+                assert!(Arc::ptr_eq(&from, &storage));
+                assert_eq!(offset, BLOCK_MAX);
+            }
+            _ => panic!("piece 0 is in storage"),
+        }
+        match swarm.block(&request(1, 10, 100)) {
+            Some(BlockData::Memory(data)) => assert_eq!(data, piece_data(1, npieces)[10..110]),
+            _ => panic!("piece 1 is in memory"),
+        }
+        for missing in [
+            request(2, 0, BLOCK_MAX),
+            request(1, PIECE_LENGTH - 10, 20),
+            request(1, usize::MAX, 1),
+            request(npieces, 0, BLOCK_MAX),
+        ] {
+            assert!(!swarm.has_block(&missing));
+            assert!(swarm.block(&missing).is_none());
+        }
+
+        swarm.block_sent(100);
+        assert_eq!(swarm.stats.uploaded_bytes, 100);
+    }
+
+    #[test]
+    fn stalled_window_blocks_are_requested_again_before_new_pieces() {
+        let npieces = 4;
+        let deadline = Duration::from_millis(20);
+        let mut swarm = swarm(npieces, Box::new(Sequential::new(2).with_deadline(deadline)));
+        let all = Bitfield::full(npieces);
+
+        let stalled = swarm.next_block(&all, addr(1)).unwrap();
+        assert_eq!((stalled.piece_i, stalled.begin), (0, 0));
+        // Not overdue yet, so the second peer gets the other half of the piece.
+        let other = swarm.next_block(&all, addr(2)).unwrap();
+        assert_eq!((other.piece_i, other.begin), (0, BLOCK_MAX));
+
+        std::thread::sleep(deadline * 2);
+        // Piece 1 is still waiting to be started, but the overdue block goes first.
+        assert_eq!(swarm.next_block(&all, addr(3)), Some(stalled));
+        assert!(swarm.is_requested_by(addr(1), &stalled));
+        assert!(swarm.is_requested_by(addr(3), &stalled));
     }
 }
@@ -1,6 +1,15 @@
 pub const BLOCK_MAX: usize = 1 << 14;
 
+pub mod dht;
+pub mod download;
+pub mod listener;
+pub mod lsd;
+pub mod magnet;
+pub mod metadata;
 pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod storage;
+pub mod token;
 pub mod torrent;
 pub mod tracker;
-pub mod download;
@@ -0,0 +1,97 @@
+//! Accepting connections from peers that found us through a tracker, the DHT or the local
+//! network.
+
+use crate::peer::PeerConnection;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// The port trackers have always been told we listen on.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// For each torrent we accept peers for, the peer id to answer with and where its connections
+/// go.
+type Torrents = HashMap<[u8; 20], ([u8; 20], mpsc::UnboundedSender<PeerConnection>)>;
+
+/// A socket peers connect to. Connections are handed to the torrent they ask for in their
+/// handshake; peers asking for any other torrent are turned away.
+///
+/// Stops accepting when dropped.
+pub struct Listener {
+    local_addr: SocketAddr,
+    torrents: Arc<Mutex<Torrents>>,
+    task: JoinHandle<()>,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        let task = tokio::spawn(accept(listener, Arc::clone(&torrents)));
+        Ok(Self {
+            local_addr,
+            torrents,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Starts accepting peers for `info_hash`, introducing ourselves as `peer_id`. Their
+    /// connections come out of the returned receiver; dropping it stops accepting them.
+    pub fn register(&self, info_hash: [u8; 20], peer_id: [u8; 20]) -> mpsc::UnboundedReceiver<PeerConnection> {
+        let (connections, incoming) = mpsc::unbounded_channel();
+        self.torrents
+            .lock()
+            .expect("listener lock poisoned")
+            .insert(info_hash, (peer_id, connections));
+        incoming
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, torrents: Arc<Mutex<Torrents>>) {
+    let mut handshakes = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                // Failing to accept one connection, e.g. for lack of file descriptors, says
+                // nothing about the next one.
+                let Ok((stream, addr)) = accepted else {
+                    continue;
+                };
+                let torrents = Arc::clone(&torrents);
+                handshakes.push(async move {
+                    PeerConnection::accept(stream, addr, |info_hash| {
+                        let mut torrents = torrents.lock().expect("listener lock poisoned");
+                        let (peer_id, connections) = torrents.get(&info_hash)?;
+                        if connections.is_closed() {
+                            torrents.remove(&info_hash);
+                            return None;
+                        }
+                        Some((*peer_id, connections.clone()))
+                    })
+                    .await
+                });
+            }
+            Some(handshake) = handshakes.next() => {
+                // Peers that fail the handshake or ask for the wrong torrent are simply dropped.
+                if let Ok((peer, connections)) = handshake {
+                    let _ = connections.send(peer);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! Local Service Discovery (BEP 14): finding peers on the local network by announcing the
+//! torrents we take part in to a multicast group.
+
+use crate::download::{DiscoveredPeer, PeerSource};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+pub const PORT: u16 = 6771;
+
+pub const MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+
+/// The organization-local IPv6 group.
+pub const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// How often we announce each torrent; BEP 14 asks for no more than once a minute.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Announces are small; anything larger is not one.
+const MAX_PACKET: usize = 1400;
+
+/// A `BT-SEARCH` message: someone on the local network listening on `port` for the torrents of
+/// `info_hashes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Set by the sender to recognize its own announces when the multicast group loops them
+    /// back.
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    /// The message as sent to the multicast group `host`, given as address and port.
+    pub fn encode(&self, host: &str) -> Vec<u8> {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n", self.port);
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {cookie}\r\n"));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Parses an announce, if `packet` is a well-formed one with a port and at least one info
+    /// hash. Header names are case-insensitive; unknown headers are ignored.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let message = std::str::from_utf8(packet).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':')?;
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().ok().filter(|&port| port != 0)?),
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+                    hex::decode_to_slice(value, &mut info_hash).ok()?;
+                    info_hashes.push(info_hash);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Our membership in the local discovery groups: announces our torrents and hands out the peers
+/// that announce the same ones.
+///
+/// Background work stops when this is dropped.
+pub struct Lsd {
+    inner: Arc<Inner>,
+    tasks: Mutex<JoinSet<()>>,
+}
+
+type Searches = HashMap<[u8; 20], Vec<mpsc::UnboundedSender<DiscoveredPeer>>>;
+
+struct Inner {
+    /// One socket per group we joined, with the group.
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    cookie: String,
+    searches: Mutex<Searches>,
+}
+
+impl Lsd {
+    /// Joins the IPv4 and IPv6 groups on [`PORT`], sharing the port with other clients on this
+    /// machine. Succeeds if at least one of the groups could be joined.
+    pub fn bind() -> std::io::Result<Self> {
+        let mut sockets = Vec::new();
+        let mut failure = None;
+        for group in [IpAddr::V4(MULTICAST_V4), IpAddr::V6(MULTICAST_V6)] {
+            match multicast_socket(group) {
+                Ok(socket) => sockets.push((socket, SocketAddr::new(group, PORT))),
+                Err(e) => failure = failure.or(Some(e)),
+            }
+        }
+        if sockets.is_empty() {
+            return Err(failure.expect("every group failed to join"));
+        }
+        let inner = Arc::new(Inner {
+            sockets,
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+            searches: Mutex::new(HashMap::new()),
+        });
+        let mut tasks = JoinSet::new();
+        for socket_i in 0..inner.sockets.len() {
+            tasks.spawn(Arc::clone(&inner).receive(socket_i));
+        }
+        Ok(Self {
+            inner,
+            tasks: Mutex::new(tasks),
+        })
+    }
+
+    /// Sends peers on the local network that announce `info_hash` to `peers` until it is closed,
+    /// and announces that we accept connections for it on `announce_port`, if given.
+    pub fn search(
+        &self,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        peers: mpsc::UnboundedSender<DiscoveredPeer>,
+    ) {
+        let inner = Arc::clone(&self.inner);
+        self.tasks
+            .lock()
+            .expect("lsd task lock poisoned")
+            .spawn(inner.search(info_hash, announce_port, peers));
+    }
+}
+
+fn multicast_socket(group: IpAddr) -> std::io::Result<UdpSocket> {
+    let domain = if group.is_ipv4() { Domain::IPV4 } else { Domain::IPV6 };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    match group {
+        IpAddr::V4(group) => {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        }
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, PORT)).into())?;
+            socket.join_multicast_v6(&group, 0)?;
+        }
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+impl Inner {
+    fn searches(&self) -> std::sync::MutexGuard<'_, Searches> {
+        self.searches.lock().expect("lsd search lock poisoned")
+    }
+
+    async fn search(
+        self: Arc<Self>,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        peers: mpsc::UnboundedSender<DiscoveredPeer>,
+    ) {
+        self.searches().entry(info_hash).or_default().push(peers.clone());
+        let announcing = async {
+            let Some(port) = announce_port else {
+                return std::future::pending().await;
+            };
+            let announce = Announce {
+                port,
+                info_hashes: vec![info_hash],
+                cookie: Some(self.cookie.clone()),
+            };
+            loop {
+                for (socket, group) in &self.sockets {
+                    // A network that is down now may be back by the next announce.
+                    let _ = socket.send_to(&announce.encode(&group.to_string()), group).await;
+                }
+                tokio::time::sleep(ANNOUNCE_INTERVAL).await;
+            }
+        };
+        tokio::select! {
+            _ = peers.closed() => {}
+            _ = announcing => {}
+        }
+        let mut searches = self.searches();
+        if let Some(senders) = searches.get_mut(&info_hash) {
+            senders.retain(|sender| !sender.is_closed());
+            if senders.is_empty() {
+                searches.remove(&info_hash);
+            }
+        }
+    }
+
+    async fn receive(self: Arc<Self>, socket_i: usize) {
+        let socket = &self.sockets[socket_i].0;
+        let mut buf = vec![0; MAX_PACKET];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // As for the DHT socket, errors here are about earlier datagrams.
+                Err(_) => continue,
+            };
+            let Some(announce) = Announce::parse(&buf[..n]) else {
+                continue;
+            };
+            if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+            let addr = SocketAddr::new(from.ip(), announce.port);
+            let searches = self.searches();
+            for info_hash in &announce.info_hashes {
+                for peers in searches.get(info_hash).into_iter().flatten() {
+                    let _ = peers.send(DiscoveredPeer {
+                        addr,
+                        source: PeerSource::Lsd,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_round_trip() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("c0ffee".to_string()),
+        };
+        let packet = announce.encode("239.192.152.143:6771");
+        assert!(packet.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n"));
+        assert!(packet.ends_with(b"\r\n\r\n"));
+        assert_eq!(Announce::parse(&packet), Some(announce));
+    }
+
+    #[test]
+    fn parses_headers_in_any_case() {
+        let packet = format!(
+            "BT-SEARCH * HTTP/1.1\r\nhost: [ff15::efc0:988f]:6771\r\nport:  51413\r\nINFOHASH: {}\r\nX-Other: 1\r\n\r\n\r\n",
+            "ab".repeat(20)
+        );
+        let announce = Announce::parse(packet.as_bytes()).unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes, [[0xab; 20]]);
+        assert_eq!(announce.cookie, None);
+    }
+
+    #[test]
+    fn rejects_incomplete_announces() {
+        let info_hash = format!("Infohash: {}\r\n", "ab".repeat(20));
+        for packet in [
+            format!("BT-SEARCH * HTTP/1.1\r\n{info_hash}\r\n"),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n".to_string(),
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n{info_hash}\r\n"),
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: abc\r\n\r\n".to_string(),
+            format!("NOTIFY * HTTP/1.1\r\nPort: 6881\r\n{info_hash}\r\n"),
+        ] {
+            assert_eq!(Announce::parse(packet.as_bytes()), None, "{packet:?}");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use reqwest::Url;
+
+/// The parts of a `magnet:` link we understand (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    /// `dn`: what to call the torrent until we have its metadata.
+    pub name: Option<String>,
+    /// `tr`: tracker urls, in the order they appear.
+    pub trackers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid magnet link: {0}")]
+pub struct InvalidMagnet(String);
+
+impl Magnet {
+    pub fn parse(link: &str) -> Result<Self, InvalidMagnet> {
+        let url = Url::parse(link).map_err(|e| InvalidMagnet(e.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(InvalidMagnet(format!("scheme is {:?}", url.scheme())));
+        }
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(
+                            parse_info_hash(hash)
+                                .ok_or_else(|| InvalidMagnet(format!("bad info hash {hash:?}")))?,
+                        );
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            info_hash: info_hash.ok_or_else(|| InvalidMagnet("no urn:btih info hash".to_string()))?,
+            name,
+            trackers,
+        })
+    }
+
+    /// Tracker tiers in the shape of [`Torrent::trackers`](crate::torrent::Torrent::trackers);
+    /// magnet links have no tiers, so every tracker gets its own.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect()
+    }
+}
+
+/// Parses an info hash given as 40 hex digits or 32 base32 characters, as magnet links and
+/// users spell them.
+pub fn parse_info_hash(hash: &str) -> Option<[u8; 20]> {
+    let mut info_hash = [0; 20];
+    match hash.len() {
+        40 => hex::decode_to_slice(hash, &mut info_hash).ok()?,
+        32 => {
+            // RFC 4648 base32: 8 characters of 5 bits each make 5 bytes.
+            let mut bits = 0u64;
+            let mut nbits = 0;
+            let mut out = 0;
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return None,
+                };
+                bits = (bits << 5) | u64::from(value);
+                nbits += 5;
+                if nbits >= 8 {
+                    nbits -= 8;
+                    info_hash[out] = (bits >> nbits) as u8;
+                    out += 1;
+                }
+            }
+        }
+        _ => return None,
+    }
+    Some(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "2794a0ca7a6b64d6e7bf7d2fd6bcb5d8d1c5d0b9";
+    const BASE32: &str = "E6KKBST2NNSNNZ57PUX5NPFV3DI4LUFZ";
+
+    #[test]
+    fn parses_hex_and_base32_info_hashes() {
+        let expected: [u8; 20] = hex::decode(HEX).unwrap().try_into().unwrap();
+        assert_eq!(parse_info_hash(HEX), Some(expected));
+        assert_eq!(parse_info_hash(&HEX.to_uppercase()), Some(expected));
+        assert_eq!(parse_info_hash(BASE32), Some(expected));
+        assert_eq!(parse_info_hash(&BASE32.to_lowercase()), Some(expected));
+    }
+
+    #[test]
+    fn rejects_malformed_info_hashes() {
+        assert_eq!(parse_info_hash(&HEX[1..]), None);
+        assert_eq!(parse_info_hash(&HEX.replace('a', "g")), None);
+        assert_eq!(parse_info_hash(&BASE32.replace('E', "1")), None);
+        assert_eq!(parse_info_hash(""), None);
+    }
+
+    #[test]
+    fn parses_magnet_links() {
+        let link = format!(
+            "magnet:?xt=urn:btih:{BASE32}&dn=sample.txt&tr=http%3A%2F%2Fa.example%2Fannounce&tr=udp%3A%2F%2Fb.example%3A80"
+        );
+        let magnet = Magnet::parse(&link).unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), HEX);
+        assert_eq!(magnet.name.as_deref(), Some("sample.txt"));
+        assert_eq!(magnet.trackers, ["http://a.example/announce", "udp://b.example:80"]);
+        assert_eq!(
+            magnet.tracker_tiers(),
+            [vec!["http://a.example/announce".to_string()], vec!["udp://b.example:80".to_string()]]
+        );
+    }
+
+    #[test]
+    fn rejects_magnet_links_without_an_info_hash() {
+        assert!(Magnet::parse("magnet:?dn=sample.txt").is_err());
+        assert!(Magnet::parse(&format!("http://example.com/?xt=urn:btih:{HEX}")).is_err());
+    }
+}
@@ -1,21 +1,100 @@
 use bittorrent_starter_rust::torrent::{Torrent, Keys};
-use bittorrent_starter_rust::tracker::{TrackerRequest, TrackerResponse};
-use bittorrent_starter_rust::{peer::*, BLOCK_MAX};
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
-use std::net::SocketAddrV4;
-use std::path::PathBuf;
-use serde_bencode;
+use bittorrent_starter_rust::tracker::server::{self, SwarmStore, TrackerConfig};
+use bittorrent_starter_rust::tracker::{self, Announcer, Tracker, TrackerClient, TrackerError, TrackerRequest};
+use bittorrent_starter_rust::dht::{self, Dht, DhtState};
+use bittorrent_starter_rust::listener::{self, Listener};
+use bittorrent_starter_rust::lsd::Lsd;
+use bittorrent_starter_rust::magnet::{self, Magnet};
+use bittorrent_starter_rust::metadata;
+use bittorrent_starter_rust::download::{self, DiscoveredPeer, DownloadOptions, DownloadStats, PeerSource, Progress};
+use bittorrent_starter_rust::peer::{generate_peer_id, PeerConnection};
+use bittorrent_starter_rust::picker::Sequential;
+use bittorrent_starter_rust::storage::Storage;
+use std::collections::HashSet;
+use std::io::SeekFrom;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
 use clap::{Parser, Subcommand};
+use ed25519_dalek::SigningKey;
+use serde_bencode::value::Value;
 use anyhow::Context;
-use sha1::{Sha1, Digest};
 use serde_json::{self, Map};
-use futures_util::{StreamExt, SinkExt};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Peer id to use instead of a random one; must be exactly 20 bytes.
+    #[arg(long, global = true)]
+    pub peer_id: Option<String>,
+
+    /// Key to announce to trackers instead of a random one.
+    #[arg(long, global = true)]
+    pub key: Option<u32>,
+
+    #[command(flatten)]
+    pub dht: DhtArgs,
+
+    #[command(flatten)]
+    pub peers: PeerArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct PeerArgs {
+    /// Do not look for peers on the local network.
+    #[arg(long = "no-lsd", global = true)]
+    pub no_lsd: bool,
+
+    /// TCP port to accept connections from peers on.
+    #[arg(long = "port", global = true, value_name = "PORT", default_value_t = listener::DEFAULT_PORT)]
+    pub listen_port: u16,
+}
+
+#[derive(clap::Args, Debug)]
+struct DhtArgs {
+    /// Do not look for peers in the DHT.
+    #[arg(long = "no-dht", global = true)]
+    pub disabled: bool,
+
+    /// UDP port of our DHT node.
+    #[arg(long = "dht-port", global = true, default_value_t = dht::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// DHT node to join the network through instead of the well-known ones; may be repeated.
+    #[arg(long = "dht-node", global = true, value_name = "HOST:PORT", value_parser = parse_host_port)]
+    pub nodes: Vec<(String, u16)>,
+
+    /// File to keep our DHT node id and routing table in between runs [default:
+    /// $XDG_CACHE_HOME/bittorrent/dht-state, or ~/.cache/bittorrent/dht-state].
+    #[arg(long = "dht-state", global = true, value_name = "PATH")]
+    pub state: Option<PathBuf>,
+}
+
+impl DhtArgs {
+    fn state_file(&self) -> Option<PathBuf> {
+        if let Some(state) = &self.state {
+            return Some(state.clone());
+        }
+        let cache = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .filter(|cache| cache.is_absolute())
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".cache")))?;
+        Some(cache.join("bittorrent").join("dht-state"))
+    }
+}
+
+fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s.rsplit_once(':').ok_or("expected HOST:PORT")?;
+    let port = port.parse().map_err(|e| format!("invalid port: {e}"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_string(), port))
 }
 
 #[derive(Subcommand, Debug)]
@@ -33,9 +112,15 @@ pub enum Commands  {
         torrent: PathBuf
     },
 
+    /// Print the swarm statistics of each torrent without joining its swarm.
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+    },
+
     Handshake {
         torrent: PathBuf,
-        ip_port: SocketAddrV4
+        ip_port: SocketAddr
     },
 
     DownloadPiece {
@@ -47,7 +132,59 @@ pub enum Commands  {
     Download {
         #[arg(short)]
         output: PathBuf,
+        /// Torrent file, magnet link, or the info hash of a torrent to fetch from peers found
+        /// through the DHT, as 40 hex digits or 32 base32 characters.
+        torrent: PathBuf,
+        /// Fetch pieces in playback order and, for single-file torrents, write each one out as
+        /// soon as it is verified.
+        #[arg(long)]
+        sequential: bool,
+    },
+
+    /// Check the data of a torrent already on disk and serve the pieces that are intact to its
+    /// swarm until interrupted.
+    Seed {
         torrent: PathBuf,
+        /// The file of a single-file torrent, or the directory holding the files of a
+        /// multi-file one.
+        data: PathBuf,
+    },
+
+    /// Store a small value in the DHT. Without a key it is found by its hash and never changes;
+    /// with one it is signed, and storing again under the same key and salt updates it.
+    DhtPut {
+        value: String,
+        /// File with the hex seed of the ed25519 key to sign with; a new key is saved there if the
+        /// file does not exist.
+        #[arg(long = "key-file", value_name = "PATH")]
+        key_file: Option<PathBuf>,
+        /// Tells apart values stored under the same key.
+        #[arg(long, requires = "key_file")]
+        salt: Option<String>,
+    },
+
+    /// Fetch a value from the DHT by its hash or, for values that can be updated, by the public
+    /// key it was stored under, either given in hex.
+    DhtGet {
+        target: String,
+        #[arg(long)]
+        salt: Option<String>,
+    },
+
+    /// Run a tracker that keeps its swarms in memory.
+    Tracker {
+        /// Address to serve `/announce` and `/scrape` on over HTTP.
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        http: SocketAddr,
+        /// Address to also serve the UDP tracker protocol on.
+        #[arg(long)]
+        udp: Option<SocketAddr>,
+        /// Seconds clients should wait between announces.
+        #[arg(long, default_value_t = TrackerConfig::DEFAULT_INTERVAL.as_secs())]
+        interval: u64,
+        /// Only track this info hash, given in hex; may be repeated.
+        #[arg(long = "allow", value_name = "INFO_HASH")]
+        whitelist: Vec<String>,
     },
 }
 
@@ -63,7 +200,7 @@ fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
                     Some((n, rest))
                 })
             {
-                return (n.into(), rest);
+                (n.into(), rest)
             }
             else {
                 panic!("Unhandled encoded value: {}", encoded_value)
@@ -77,7 +214,7 @@ fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
                 values.push(v);
                 rest = remainder;
             }
-            return (values.into(), &rest[1..]);
+            (values.into(), &rest[1..])
         }
         Some('d') => {
             let mut dicts = Map::new();
@@ -94,12 +231,12 @@ fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
                 dicts.insert(k, v);
                 rest = remainder;
             }
-            return (dicts.into(), &rest[1..]);
+            (dicts.into(), &rest[1..])
         }
         Some('0'..='9') => {
             if let Some((len, rest)) = encoded_value.split_once(':') {
                 if let Ok(len) = len.parse::<usize>() {
-                    return (rest[..len].to_string().into(), &rest[len..]);
+                    (rest[..len].to_string().into(), &rest[len..])
                 }
                 else {
                     panic!("Unhandled encoded value: {}", encoded_value)
@@ -118,20 +255,37 @@ fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
 #[tokio::main]
 async fn main() -> anyhow::Result<()>{
     let args = Args::parse();
+    let peer_id: [u8; 20] = match &args.peer_id {
+        Some(peer_id) => <[u8; 20]>::try_from(peer_id.as_bytes())
+            .ok()
+            .context("peer id must be exactly 20 bytes")?,
+        None => generate_peer_id(),
+    };
+    let ipv6 = tracker::local_ipv6();
+    // Requests for port 0 tell trackers that we do not accept connections.
+    let tracker_request = |left, port| {
+        let mut request = TrackerRequest::new(peer_id, port, left, ipv6);
+        if let Some(key) = args.key {
+            request.key = key;
+        }
+        request
+    };
     match args.command {
         Commands::Decode { value }  => {
             let decoded_value = decode_bencoded_value(&value);
-            println!("{}", decoded_value.0.to_string());
+            println!("{}", decoded_value.0);
         } 
         Commands::Info { torrent } => {
             let f = std::fs::read(torrent).context("open torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            println!("Tracker URL: {}", t.announce);
+            let t = Torrent::from_bytes(&f)?;
+            if let Some(announce) = &t.announce {
+                println!("Tracker URL: {}", announce);
+            }
             if let Keys::SingleFile { length } = t.info.keys {
                 println!("Length: {}", length);
             }
             let info_hash  = t.info_hash();
-            println!("Info Hash: {}", hex::encode(&info_hash));
+            println!("Info Hash: {}", hex::encode(info_hash));
             println!("Piece Length: {}", t.info.piece_length);
             println!("Piece Hashes:");
             for hash in t.info.pieces.0 {
@@ -139,327 +293,626 @@ async fn main() -> anyhow::Result<()>{
             }
         }
         Commands::Peers { torrent } => {
-            let f = std::fs::read(torrent).context("open torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
-            let encoded_info = serde_bencode::to_bytes(&t.info).context("reencode info")?;
-            let mut hasher = Sha1::new();
-            hasher.update(&encoded_info);
-            let info_hash  = hasher.finalize();
-            let request = TrackerRequest::new(length);
-            let url_params = serde_urlencoded::to_string(&request).unwrap();
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                urlencode(&info_hash.into()),
-            );
-            let response = reqwest::get(tracker_url).await?.bytes().await?;
-            let response: TrackerResponse = serde_bencode::from_bytes(&response).unwrap();
-            for peer in response.peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
+            let t = Torrent::read(torrent).await?;
+            let request = tracker_request(t.length(), 0);
+            let trackers = tracker::clients(&t.trackers())?;
+            let info_hash = t.info_hash();
+            let responses = futures_util::future::join_all(
+                trackers
+                    .iter()
+                    .map(|tracker| tracker.announce(info_hash, &request)),
+            )
+            .await;
+            // Trackers each know part of the swarm, so the largest count is the best we have.
+            let mut seeders = None;
+            let mut leechers = None;
+            let mut seen = HashSet::new();
+            let mut failures = Vec::new();
+            for (tracker, response) in trackers.iter().zip(responses) {
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => {
+                        failures.push(anyhow::Error::new(e).context(format!("announce to {}", tracker.url())));
+                        continue;
+                    }
+                };
+                if let Some(warning) = &response.warning_message {
+                    eprintln!("Tracker warning from {}: {warning}", tracker.url());
+                }
+                for peer in response.all_peers() {
+                    if seen.insert(peer.addr.clone()) {
+                        println!("{}", peer.addr);
+                    }
+                }
+                seeders = seeders.max(response.complete);
+                leechers = leechers.max(response.incomplete);
+            }
+            if failures.len() == trackers.len() {
+                return Err(failures.swap_remove(0));
+            }
+            for e in failures {
+                eprintln!("Tracker failed: {e:#}");
+            }
+            if let Some(seeders) = seeders {
+                println!("Seeders: {seeders}");
+            }
+            if let Some(leechers) = leechers {
+                println!("Leechers: {leechers}");
             }
         }
-        Commands::Handshake { torrent, ip_port } => {
-            let f = std::fs::read(torrent).context("open torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            let encoded_info = serde_bencode::to_bytes(&t.info).context("reencode info")?;
-            let mut hasher = Sha1::new();
-            hasher.update(&encoded_info);
-            let info_hash  = hasher.finalize();
-            let mut peer = tokio::net::TcpStream::connect(ip_port).await.context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash.into(), *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes).await.context("write handshake")?;
-                peer.read_exact(handshake_bytes).await.context("read handshake")?;
+        Commands::Scrape { torrents } => {
+            // One scrape per tracker covers all of its torrents.
+            let mut by_tracker: Vec<(String, Vec<[u8; 20]>)> = Vec::new();
+            for torrent in &torrents {
+                let t = Torrent::read(torrent)
+                    .await
+                    .with_context(|| format!("read {}", torrent.display()))?;
+                let info_hash = t.info_hash();
+                // The first tracker is the one the torrent prefers.
+                let announce = t
+                    .trackers()
+                    .into_iter()
+                    .flatten()
+                    .next()
+                    .with_context(|| format!("{} has no tracker", torrent.display()))?;
+                match by_tracker.iter_mut().find(|(url, _)| *url == announce) {
+                    Some((_, info_hashes)) => info_hashes.push(info_hash),
+                    None => by_tracker.push((announce, vec![info_hash])),
+                }
+            }
+            for (announce, info_hashes) in by_tracker {
+                let stats = tracker::client(&announce)?
+                    .scrape(&info_hashes)
+                    .await
+                    .with_context(|| format!("scrape {announce}"))?;
+                for (info_hash, stats) in info_hashes.iter().zip(stats) {
+                    println!(
+                        "{}: complete {}, downloaded {}, incomplete {}",
+                        hex::encode(info_hash),
+                        stats.complete,
+                        stats.downloaded,
+                        stats.incomplete
+                    );
+                }
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+        }
+        Commands::Handshake { torrent, ip_port } => {
+            let t = Torrent::read(torrent).await?;
+            let peer = PeerConnection::connect(ip_port, t.info_hash(), peer_id).await?;
+            println!("Peer ID: {}", hex::encode(peer.peer_id()));
         }
         Commands::DownloadPiece { output, torrent, piece_index } => {
-            let f = std::fs::read(torrent).context("open torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
+            let t = Torrent::read(torrent).await?;
+            let mut options = DownloadOptions {
+                peer_id,
+                ..Default::default()
+            };
+            // A single piece is not worth joining the DHT or the local network for.
+            let discovery = discover_peers(&t, |port| tracker_request(t.length(), port), None, &args.dht, None, &mut options).await?;
+            let result = tokio::select! {
+                result = download::piece(&t, &[], piece_index, options) => result,
+                _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
             };
+            discovery.stop().await;
+            let (piece, stats) = result?;
+            tokio::fs::write(&output, piece)
+                .await
+                .context("write out downloaded piece")?;
+            println!("Piece {piece_index} downloaded to {}.", output.display());
+            print_stats(&stats);
+        }
+        Commands::Download { output, torrent, sequential } => {
+            // We do not know the size before we have the metadata, only that we are no seed.
+            let (t, dht) = torrent_or_metadata(&torrent, &args.dht, tracker_request(1, 0)).await?;
+            let mut options = DownloadOptions {
+                peer_id,
+                ..Default::default()
+            };
+            let discovery = discover_peers(&t, |port| tracker_request(t.length(), port), dht, &args.dht, Some(&args.peers), &mut options).await?;
+            let mut writer = None;
+            if sequential {
+                options.picker = Box::new(Sequential::default());
+                if let Keys::SingleFile { length } = t.info.keys {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    options.verified_pieces = Some(tx);
+                    writer = Some(tokio::spawn(stream_pieces(
+                        output.clone(),
+                        length,
+                        t.info.piece_length,
+                        t.info.pieces.0.len(),
+                        rx,
+                    )));
+                }
+            }
 
-            assert!(piece_index < t.info.pieces.0.len());
-
-            let encoded_info = serde_bencode::to_bytes(&t.info).context("reencode info")?;
-            let mut hasher = Sha1::new();
-            hasher.update(&encoded_info);
-            let info_hash  = hasher.finalize();
-            let request = TrackerRequest::new(length);
-            let url_params = serde_urlencoded::to_string(&request).unwrap();
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                urlencode(&info_hash.into()),
-            );
-            let response = reqwest::get(tracker_url).await?.bytes().await?;
-            let response: TrackerResponse = serde_bencode::from_bytes(&response).unwrap();
-            let peer = &response.peers.0[0];
-            let mut peer = tokio::net::TcpStream::connect(peer).await.context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash.into(), *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes)
+            let result = tokio::select! {
+                result = download::all(&t, &[], options) => result,
+                _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+            };
+            discovery.stop().await;
+            let files = result?;
+            if let Some(writer) = writer {
+                writer.await.context("piece writer panicked")??;
+            } else if let Keys::SingleFile { .. } = t.info.keys {
+                tokio::fs::write(&output, files.bytes())
                     .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await.context("read handshake")?;
+                    .context("write out downloaded file")?;
+            } else {
+                for file in &files {
+                    let path: PathBuf = output.join(file.path().iter().collect::<PathBuf>());
+                    if let Some(parent) = path.parent() {
+                        tokio::fs::create_dir_all(parent)
+                            .await
+                            .context("create output directory")?;
+                    }
+                    tokio::fs::write(&path, file.bytes())
+                        .await
+                        .with_context(|| format!("write out {}", path.display()))?;
+                }
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer alway sends bitfield")
-                .context("peer message is invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
+            println!("Downloaded {} to {}.", t.info.name, output.display());
+            print_stats(files.stats());
+        }
+        Commands::Seed { torrent, data } => {
+            let t = Torrent::read(torrent).await?;
+            let storage = Arc::new(Storage::new(&t, &data));
+            let have = storage
+                .verify(&t)
                 .await
-                .context("send interested message")?;
-
-            let unchoke = peer
-                .next()
+                .with_context(|| format!("read {}", data.display()))?;
+            let npieces = t.info.pieces.0.len();
+            let nhave = have.pieces().count();
+            println!("Verified {nhave}/{npieces} pieces.");
+            anyhow::ensure!(nhave > 0, "{} has no data of this torrent", data.display());
+            let left: usize = (0..npieces)
+                .filter(|&piece_i| !have.has_piece(piece_i))
+                .map(|piece_i| t.piece_length(piece_i))
+                .sum();
+
+            let mut options = DownloadOptions {
+                peer_id,
+                ..Default::default()
+            };
+            let dht = torrent_dht(&t, &args.dht).await;
+            let discovery = discover_peers(&t, |port| tracker_request(left, port), dht, &args.dht, Some(&args.peers), &mut options).await?;
+            println!("Seeding {}.", t.info.name);
+            let progress = options.progress.clone();
+            let result = tokio::select! {
+                result = download::seed(&t, &[], storage, &have, options) => result.map(Some),
+                _ = tokio::signal::ctrl_c() => Ok(None),
+            };
+            discovery.stop().await;
+            match result? {
+                Some(stats) => print_stats(&stats),
+                None => {
+                    let uploaded = progress.map_or(0, |progress| progress.uploaded());
+                    println!("Stopped seeding after uploading {uploaded} bytes.");
+                }
+            }
+        }
+        Commands::DhtPut { value, key_file, salt } => {
+            let dht = start_dht(&args.dht, &[])
                 .await
-                .expect("peer alway sends unchoke")
-                .context("peer message is invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
-
-            let piece_hash = &t.info.pieces.0[piece_index];
-            let piece_size = if piece_index == t.info.pieces.0.len() - 1 {
-                let md = length % t.info.piece_length;
-                if md == 0 {
-                    t.info.piece_length
-                } else {
-                    md
+                .context("the dht is needed to store values")?;
+            let value = Value::Bytes(value.into_bytes());
+            match key_file {
+                None => {
+                    let target = dht.put_immutable(value).await.context("store value in the dht")?;
+                    println!("Target: {}", hex::encode(target));
                 }
+                Some(key_file) => {
+                    let key = signing_key(&key_file).await?;
+                    let salt = salt.unwrap_or_default();
+                    let item = dht
+                        .put_mutable(&key, salt.as_bytes(), value)
+                        .await
+                        .context("store value in the dht")?;
+                    println!("Public key: {}", hex::encode(item.public_key));
+                    println!("Sequence number: {}", item.seq);
+                    println!("Target: {}", hex::encode(item.target()));
+                }
+            }
+            save_dht(&dht, args.dht.state_file()).await;
+        }
+        Commands::DhtGet { target, salt } => {
+            let target = hex::decode(&target).context("target must be hex")?;
+            let dht = start_dht(&args.dht, &[])
+                .await
+                .context("the dht is needed to fetch values")?;
+            let result = if let Ok(public_key) = <[u8; 32]>::try_from(target.as_slice()) {
+                let salt = salt.unwrap_or_default();
+                dht.get_mutable(&public_key, salt.as_bytes()).await.map(|item| {
+                    println!("Sequence number: {}", item.seq);
+                    item.value
+                })
+            } else if let Ok(target) = <[u8; 20]>::try_from(target.as_slice()) {
+                anyhow::ensure!(salt.is_none(), "only values stored under a public key have a salt");
+                dht.get_immutable(target).await
             } else {
-                t.info.piece_length   
+                anyhow::bail!("target must be a 20-byte hash or a 32-byte public key");
             };
-
-            let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-            let mut all_blocks = Vec::with_capacity(piece_size);
-
-            for block in 0..nblocks {
-                let block_size = if block == nblocks - 1 {
-                    let md = piece_size % BLOCK_MAX;
-                    if md == 0 {
-                        BLOCK_MAX
-                    } else {
-                        md
-                    }
-                } else {
-                    BLOCK_MAX
-                };
-                let mut request = Request::new(
-                    piece_index as u32,
-                    (block * BLOCK_MAX) as u32,
-                    block_size as u32,
+            save_dht(&dht, args.dht.state_file()).await;
+            match result.context("fetch value from the dht")? {
+                Value::Bytes(bytes) => println!("{}", String::from_utf8_lossy(&bytes)),
+                value => println!("{value:?}"),
+            }
+        }
+        Commands::Tracker { http, udp, interval, whitelist } => {
+            let mut config = TrackerConfig::new(Duration::from_secs(interval));
+            if !whitelist.is_empty() {
+                config.whitelist = Some(
+                    whitelist
+                        .iter()
+                        .map(|info_hash| {
+                            let mut decoded = [0; 20];
+                            hex::decode_to_slice(info_hash, &mut decoded)
+                                .with_context(|| format!("{info_hash} is not a hex info hash"))?;
+                            Ok(decoded)
+                        })
+                        .collect::<anyhow::Result<_>>()?,
                 );
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
+            }
+            let store = Arc::new(SwarmStore::new(config));
+            let listener = TcpListener::bind(http)
                 .await
-                .with_context(|| format!("send request for block {block}"))?;
+                .with_context(|| format!("listen on {http}"))?;
+            println!("Tracking on http://{http}/announce");
+            let udp = match udp {
+                Some(addr) => {
+                    let socket = UdpSocket::bind(addr)
+                        .await
+                        .with_context(|| format!("listen on {addr}"))?;
+                    println!("Tracking on udp://{addr}");
+                    Some(socket)
+                }
+                None => None,
+            };
+            let http = server::serve_http(listener, Arc::clone(&store));
+            match udp {
+                Some(socket) => {
+                    tokio::try_join!(
+                        async { http.await.context("accept tracker connection") },
+                        async { server::serve_udp(socket, store).await.context("receive tracker request") },
+                    )?;
+                }
+                None => http.await.context("accept tracker connection")?,
+            }
+        }
+    }
+    Ok(())
+}
 
-                let piece = peer
-                    .next()
-                    .await
-                    .expect("peer always sends a piece")
-                    .context("peer message was invalid")?;
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
-
-                let piece = Piece::ref_from_bytes(&piece.payload[..])
-                    .expect("always get all Piece response fields from peer");
-                assert_eq!(piece.index() as usize, piece_index);
-                assert_eq!(piece.begin() as usize, block * BLOCK_MAX);
-                assert_eq!(piece.block().len(), block_size);
-                all_blocks.extend(piece.block());
-            }
-            assert_eq!(all_blocks.len(), piece_size);
-
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .expect("GenericArray<_, 20> == [_; 20]");
-            assert_eq!(&hash, piece_hash);
-
-            tokio::fs::write(&output, all_blocks)
-                .await
-                .context("write out downloaded piece")?;
-            println!("Piece {piece_index} downloaded to {}.", output.display());
+/// Where a download gets its peers from; stop it once the download is over.
+struct Discovery {
+    announcer: Option<Announcer>,
+    dht: Option<Dht>,
+    /// Only kept around to keep searching.
+    _lsd: Option<Lsd>,
+    /// Only kept around to keep accepting peers.
+    _listener: Option<Listener>,
+    dht_state: Option<PathBuf>,
+}
+
+impl Discovery {
+    /// Also saves what the DHT node learned for the next run.
+    async fn stop(self) {
+        if let Some(announcer) = self.announcer {
+            announcer.stop().await;
         }
-        Commands::Download { output, torrent } => {
-            let f = std::fs::read(torrent).context("open torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
-            let length = if let Keys::SingleFile { length } = t.info.keys {
-                length
-            } else {
-                todo!()
-            };
+        if let Some(dht) = self.dht {
+            save_dht(&dht, self.dht_state).await;
+        }
+    }
+}
 
-            let encoded_info = serde_bencode::to_bytes(&t.info).context("reencode info")?;
-            let mut hasher = Sha1::new();
-            hasher.update(&encoded_info);
-            let info_hash  = hasher.finalize();
-            let request = TrackerRequest::new(length);
-            let url_params = serde_urlencoded::to_string(&request).unwrap();
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                t.announce,
-                url_params,
-                urlencode(&info_hash.into()),
-            );
-            let response = reqwest::get(tracker_url).await?.bytes().await?;
-            let response: TrackerResponse = serde_bencode::from_bytes(&response).unwrap();
-            let peer = &response.peers.0[0];
-            let mut peer = tokio::net::TcpStream::connect(peer).await.context("connect to peer")?;
-            let mut handshake = Handshake::new(info_hash.into(), *b"00112233445566778899");
-            {
-                let handshake_bytes = handshake.as_bytes_mut();
-                peer.write_all(handshake_bytes)
-                    .await
-                    .context("write handshake")?;
-                peer.read_exact(handshake_bytes)
-                    .await.context("read handshake")?;
+/// Announces `t` to all of its trackers and searches `dht` and, unless disabled, the local
+/// network for it, letting the download pick up the peers found and report its progress. Peers
+/// can also connect to us on the port we announce, if we can listen on it. Without `args`, only
+/// the trackers are asked, and we neither listen nor search the local network. Trackers that are
+/// down for now do not keep the download from waiting for them, and neither do trackers that
+/// refuse it while there are other sources of peers.
+async fn discover_peers(
+    t: &Torrent,
+    request: impl FnOnce(u16) -> TrackerRequest,
+    dht: Option<Dht>,
+    dht_args: &DhtArgs,
+    args: Option<&PeerArgs>,
+    options: &mut DownloadOptions,
+) -> anyhow::Result<Discovery> {
+    let (peers, peer_source) = mpsc::unbounded_channel();
+    let listener = match args {
+        Some(args) => match Listener::bind((Ipv4Addr::UNSPECIFIED, args.listen_port).into()).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("Not accepting connections from peers: {:#}", anyhow::Error::new(e).context(format!("listen on tcp port {}", args.listen_port)));
+                None
             }
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer);
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer alway sends bitfield")
-                .context("peer message is invalid")?;
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
-                .await
-                .context("send interested message")?;
+        },
+        None => None,
+    };
+    let announce_port = listener.as_ref().map(|listener| listener.local_addr().port());
+    if let Some(listener) = &listener {
+        options.incoming = Some(listener.register(t.info_hash(), options.peer_id));
+    }
+    let request = request(announce_port.unwrap_or(0));
+    let progress = Arc::new(Progress::new(request.left as u64));
+    // Peers of private torrents may only come from their trackers.
+    let dht = dht.filter(|_| !t.is_private());
+    if let Some(dht) = &dht {
+        dht.search(t.info_hash(), announce_port, peers.clone());
+    }
+    let lsd = if args.is_some_and(|args| !args.no_lsd) && !t.is_private() {
+        match Lsd::bind() {
+            Ok(lsd) => Some(lsd),
+            Err(e) => {
+                eprintln!("Local service discovery unavailable: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(lsd) = &lsd {
+        lsd.search(t.info_hash(), announce_port, peers.clone());
+    }
+    // Peers that found us some other way can still connect.
+    let other_sources = dht.is_some() || lsd.is_some() || listener.is_some();
+
+    let trackers = match tracker::clients_by_tier(&t.trackers()) {
+        Ok(trackers) => trackers,
+        Err(TrackerError::NoTrackers) if other_sources => Vec::new(),
+        Err(e) if other_sources => {
+            eprintln!("No usable tracker, relying on other sources of peers: {:#}", anyhow::Error::new(e));
+            Vec::new()
+        }
+        Err(e) => return Err(e).context("announce to trackers"),
+    };
+    let announcer = if trackers.is_empty() {
+        None
+    } else {
+        let (announcer, first) =
+            Announcer::start(trackers, t.info_hash(), request, Arc::clone(&progress), peers).await;
+        match first {
+            Ok(response) => {
+                if let Some(warning) = &response.warning_message {
+                    eprintln!("Tracker warning: {warning}");
+                }
+            }
+            Err(e) if e.is_transient() => {
+                eprintln!("Trackers unreachable, retrying in the background: {:#}", anyhow::Error::new(e));
+            }
+            Err(e) if other_sources => {
+                eprintln!("Trackers refused the torrent, relying on other sources of peers: {:#}", anyhow::Error::new(e));
+            }
+            Err(e) => {
+                announcer.stop().await;
+                return Err(e).context("announce to trackers");
+            }
+        }
+        Some(announcer)
+    };
+    options.progress = Some(progress);
+    options.peer_source = Some(peer_source);
+    Ok(Discovery {
+        announcer,
+        dht,
+        _lsd: lsd,
+        _listener: listener,
+        dht_state: dht_args.state_file(),
+    })
+}
 
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer alway sends unchoke")
-                .context("peer message is invalid")?;
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
-
-            let piece_hashes = &t.info.pieces.0;
-            for (piece_index, piece_hash) in piece_hashes.iter().enumerate() {
-                let piece_size = if piece_index == piece_hashes.len() - 1 {
-                    let md = length % t.info.piece_length;
-                    if md == 0 {
-                        t.info.piece_length
-                    } else {
-                        md
-                    }
-                } else {
-                    t.info.piece_length   
-                };
+/// Reads the torrent file at `torrent` and starts the DHT node for downloading it. If `torrent` is
+/// a magnet link, or an info hash with no such file, fetches the info dictionary from peers that
+/// the DHT and the trackers of the link know instead; the torrent then keeps those trackers.
+async fn torrent_or_metadata(
+    torrent: &Path,
+    dht_args: &DhtArgs,
+    request: TrackerRequest,
+) -> anyhow::Result<(Torrent, Option<Dht>)> {
+    let link = torrent.to_str().unwrap_or_default();
+    let (info_hash, tiers) = if link.starts_with("magnet:") {
+        let magnet = Magnet::parse(link)?;
+        (magnet.info_hash, magnet.tracker_tiers())
+    } else {
+        match magnet::parse_info_hash(link) {
+            Some(info_hash) if !tokio::fs::try_exists(torrent).await.unwrap_or(false) => (info_hash, Vec::new()),
+            _ => {
+                let t = Torrent::read(torrent).await?;
+                let dht = torrent_dht(&t, dht_args).await;
+                return Ok((t, dht));
+            }
+        }
+    };
+    let dht = start_dht(dht_args, &[]).await;
+    let trackers = match tracker::clients(&tiers) {
+        Ok(trackers) => trackers,
+        Err(TrackerError::NoTrackers) if dht.is_some() => Vec::new(),
+        Err(e) if dht.is_some() => {
+            eprintln!("No usable tracker, relying on the dht: {:#}", anyhow::Error::new(e));
+            Vec::new()
+        }
+        Err(e) => return Err(e).context("the dht or a tracker is needed to download by info hash"),
+    };
+    let (peers, mut found) = mpsc::unbounded_channel();
+    if let Some(dht) = &dht {
+        dht.search(info_hash, None, peers.clone());
+    }
+    let announce = tokio::spawn(ask_trackers(trackers, info_hash, request.clone(), peers));
+    let info = tokio::select! {
+        info = metadata::find(info_hash, request.peer_id, &mut found) => info,
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("interrupted")),
+    };
+    // Dropping the receiver ends the search; the download starts one of its own.
+    announce.abort();
+    drop(found);
+    let info = match info {
+        Ok(info) => info,
+        Err(e) => {
+            if let Some(dht) = &dht {
+                save_dht(dht, dht_args.state_file()).await;
+            }
+            return Err(e);
+        }
+    };
+    let mut t = Torrent::from_metadata(info, info_hash);
+    if !tiers.is_empty() {
+        t.announce_list = Some(tiers);
+    }
+    Ok((t, dht))
+}
 
-                let nblocks = (piece_size + (BLOCK_MAX - 1)) / BLOCK_MAX;
-                let mut all_blocks = Vec::with_capacity(piece_size);
-
-                for block in 0..nblocks {
-                    let block_size = if block == nblocks - 1 {
-                        let md = piece_size % BLOCK_MAX;
-                        if md == 0 {
-                            BLOCK_MAX
-                        } else {
-                            md
-                        }
-                    } else {
-                        BLOCK_MAX
-                    };
-                    let mut request = Request::new(
-                        piece_index as u32,
-                        (block * BLOCK_MAX) as u32,
-                        block_size as u32,
-                    );
-                    let request_bytes = Vec::from(request.as_bytes_mut());
-                    peer.send(Message {
-                        tag: MessageTag::Request,
-                        payload: request_bytes,
-                    })
-                        .await
-                        .with_context(|| format!("send request for block {block}"))?;
+/// Asks each of `trackers` once for peers of `info_hash` and sends them to `peers`. The download
+/// announces to them properly once it knows what it is downloading.
+async fn ask_trackers(
+    trackers: Vec<Tracker>,
+    info_hash: [u8; 20],
+    request: TrackerRequest,
+    peers: mpsc::UnboundedSender<DiscoveredPeer>,
+) {
+    let asks = trackers.iter().map(|tracker| async {
+        let Ok(response) = tracker.announce(info_hash, &request).await else {
+            return;
+        };
+        for addr in response.resolve_peers().await {
+            let _ = peers.send(DiscoveredPeer {
+                addr,
+                source: PeerSource::Tracker(tracker.url().to_string()),
+            });
+        }
+    });
+    futures_util::future::join_all(asks).await;
+}
 
-                    let piece = peer
-                        .next()
-                        .await
-                        .expect("peer always sends a piece")
-                        .context("peer message was invalid")?;
-                    assert_eq!(piece.tag, MessageTag::Piece);
-                    assert!(!piece.payload.is_empty());
-
-                    let piece = Piece::ref_from_bytes(&piece.payload[..])
-                        .expect("always get all Piece response fields from peer");
-                    assert_eq!(piece.index() as usize, piece_index);
-                    assert_eq!(piece.begin() as usize, block * BLOCK_MAX);
-                    assert_eq!(piece.block().len(), block_size);
-                    all_blocks.extend(piece.block());
-                }
-                assert_eq!(all_blocks.len(), piece_size);
-
-                let mut hasher = Sha1::new();
-                hasher.update(&all_blocks);
-                let hash: [u8; 20] = hasher
-                    .finalize()
-                    .try_into()
-                    .expect("GenericArray<_, 20> == [_; 20]");
-                assert_eq!(&hash, piece_hash);
-                
-                let mut file = tokio::fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&output)
-                    .await
-                    .context("open output file")?;
+/// Starts the DHT node for downloading `t`, with the nodes the torrent suggests. Private torrents
+/// stay off the DHT, so they get none.
+async fn torrent_dht(t: &Torrent, args: &DhtArgs) -> Option<Dht> {
+    if t.is_private() {
+        return None;
+    }
+    start_dht(args, t.nodes.as_deref().unwrap_or_default()).await
+}
 
-                tokio::io::AsyncWriteExt::write_all(&mut file, &all_blocks)
-                    .await
-                    .context("write out downloaded piece")?;
+/// Starts a DHT node from the state saved by an earlier run, bootstrapping from the nodes the user
+/// gave, or the well-known ones, and from `extra_nodes` when that is not enough. A node that
+/// cannot start is no reason to give up on a download, so this only warns.
+async fn start_dht(args: &DhtArgs, extra_nodes: &[(String, u16)]) -> Option<Dht> {
+    if args.disabled {
+        return None;
+    }
+    let mut state = None;
+    if let Some(path) = args.state_file() {
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            match DhtState::read(&path).await {
+                Ok(saved) => state = Some(saved),
+                Err(e) => eprintln!("Ignoring DHT state in {}: {e:#}", path.display()),
             }
-            println!("Downloaded {} to {}.", t.info.name, output.display());
         }
     }
-    Ok(())
+    let mut bootstrap = extra_nodes.to_vec();
+    if args.nodes.is_empty() {
+        bootstrap.extend(dht::BOOTSTRAP_NODES.iter().map(|&(host, port)| (host.to_string(), port)));
+    } else {
+        bootstrap.extend(args.nodes.iter().cloned());
+    }
+    match Dht::bind((Ipv4Addr::UNSPECIFIED, args.port).into(), bootstrap, state).await {
+        Ok(dht) => Some(dht),
+        Err(e) => {
+            eprintln!("DHT unavailable: {:#}", anyhow::Error::new(e).context(format!("bind udp port {}", args.port)));
+            None
+        }
+    }
+}
+
+/// The ed25519 key whose hex seed is in `path`, generating one there if the file does not exist.
+async fn signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    if tokio::fs::try_exists(path).await.unwrap_or(false) {
+        let seed_hex = tokio::fs::read_to_string(path).await.context("read key file")?;
+        let mut seed = [0; 32];
+        hex::decode_to_slice(seed_hex.trim(), &mut seed).context("key file must hold 64 hex digits")?;
+        Ok(SigningKey::from_bytes(&seed))
+    } else {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        // Whoever can read the seed can sign in our name, so only we get to.
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path).await.context("create key file")?;
+        file.write_all(hex::encode(key.to_bytes()).as_bytes())
+            .await
+            .context("write key file")?;
+        Ok(key)
+    }
+}
+
+/// Saves what `dht` learned to `path` for the next run.
+async fn save_dht(dht: &Dht, path: Option<PathBuf>) {
+    let Some(path) = path else {
+        return;
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Err(e) = tokio::fs::create_dir_all(dir).await {
+            eprintln!("Could not save DHT state to {}: {e}", path.display());
+            return;
+        }
+    }
+    if let Err(e) = dht.state().write(&path).await {
+        eprintln!("Could not save DHT state to {}: {e:#}", path.display());
+    }
+}
+
+fn print_stats(stats: &DownloadStats) {
+    if stats.hash_failures > 0 {
+        println!(
+            "Hash failures: {} ({} bytes wasted)",
+            stats.hash_failures, stats.wasted_bytes
+        );
+    }
+    for peer in &stats.banned_peers {
+        println!("Banned peer: {peer}");
+    }
+    if stats.uploaded_bytes > 0 {
+        println!("Uploaded: {} bytes", stats.uploaded_bytes);
+    }
+    let mut found: Vec<(String, usize)> = Vec::new();
+    for source in stats.peer_sources.values().flatten() {
+        let source = source.to_string();
+        match found.iter_mut().find(|(known, _)| *known == source) {
+            Some((_, count)) => *count += 1,
+            None => found.push((source, 1)),
+        }
+    }
+    found.sort();
+    for (source, count) in found {
+        println!("Peers from {source}: {count}");
+    }
 }
 
-fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+/// Writes verified pieces of a single-file torrent into `output` at their offsets as they arrive.
+async fn stream_pieces(
+    output: impl AsRef<Path>,
+    length: usize,
+    piece_length: usize,
+    npieces: usize,
+    mut pieces: mpsc::UnboundedReceiver<(usize, Vec<u8>)>,
+) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create(output)
+        .await
+        .context("create output file")?;
+    file.set_len(length as u64)
+        .await
+        .context("size output file")?;
+    for _ in 0..npieces {
+        let Some((piece_i, data)) = pieces.recv().await else {
+            break;
+        };
+        file.seek(SeekFrom::Start((piece_i * piece_length) as u64))
+            .await
+            .context("seek to piece")?;
+        file.write_all(&data)
+            .await
+            .with_context(|| format!("write out piece {piece_i}"))?;
     }
-    encoded
+    file.flush().await.context("flush output file")
 }
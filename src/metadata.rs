@@ -0,0 +1,249 @@
+//! Fetching the info dictionary of a torrent from its peers with ut_metadata (BEP 9), for
+//! downloads that start from nothing but an info hash.
+
+use crate::download::DiscoveredPeer;
+use crate::peer::{ExtensionHandshake, MessageTag, PeerConnection, EXTENSION_HANDSHAKE};
+use crate::torrent::Info;
+use anyhow::Context;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub const EXTENSION_NAME: &str = "ut_metadata";
+
+/// The extended message id we ask peers to use for ut_metadata messages to us.
+const LOCAL_ID: u8 = 1;
+
+/// The metadata is exchanged in pieces of this many bytes; only the last piece may be shorter.
+pub const PIECE_LEN: usize = 1 << 14;
+
+/// Info dictionaries larger than this are refused, so that a peer cannot make us buffer
+/// arbitrary amounts of data.
+const MAX_METADATA_LEN: usize = 8 << 20;
+
+/// How long one peer gets to hand over all of the metadata.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Peers asked for the metadata at once.
+const MAX_FETCHES: usize = 5;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+/// The bencoded dictionary at the start of every ut_metadata message. Data messages carry the
+/// piece itself right after it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Asks the peers that come in on `peers` for the info dictionary of `info_hash`, a few at a
+/// time, until one of them hands over metadata that matches the hash.
+pub async fn find(
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    peers: &mut mpsc::UnboundedReceiver<DiscoveredPeer>,
+) -> anyhow::Result<Info> {
+    let mut tried = HashSet::new();
+    let mut fetches = FuturesUnordered::new();
+    let mut last_error = None;
+    let mut peers_done = false;
+    loop {
+        tokio::select! {
+            peer = peers.recv(), if !peers_done && fetches.len() < MAX_FETCHES => {
+                match peer {
+                    Some(peer) if tried.insert(peer.addr) => fetches.push(fetch(peer.addr, info_hash, peer_id)),
+                    Some(_) => {}
+                    None => peers_done = true,
+                }
+            }
+            Some(result) = fetches.next() => match result {
+                Ok(info) => return Ok(info),
+                Err(e) => last_error = Some(e),
+            },
+            else => {
+                return Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no peers were found")))
+                    .context("no peer sent the metadata");
+            }
+        }
+    }
+}
+
+/// Fetches the info dictionary of `info_hash` from the peer at `addr` and checks it against the
+/// hash.
+pub async fn fetch(addr: SocketAddr, info_hash: [u8; 20], peer_id: [u8; 20]) -> anyhow::Result<Info> {
+    tokio::time::timeout(FETCH_TIMEOUT, async {
+        let mut peer = PeerConnection::connect(addr, info_hash, peer_id).await?;
+        anyhow::ensure!(peer.supports_extensions(), "peer does not support extensions");
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([(EXTENSION_NAME.to_string(), i64::from(LOCAL_ID))]),
+            ..Default::default()
+        };
+        let handshake = serde_bencode::to_bytes(&handshake).context("encode extension handshake")?;
+        peer.send_extended(EXTENSION_HANDSHAKE, &handshake).await?;
+
+        let handshake: ExtensionHandshake = loop {
+            let (id, payload) = recv_extended(&mut peer).await?;
+            if id == EXTENSION_HANDSHAKE {
+                break serde_bencode::from_bytes(payload.as_slice()).context("parse extension handshake")?;
+            }
+        };
+        let remote_id = handshake
+            .id(EXTENSION_NAME)
+            .context("peer does not support ut_metadata")?;
+        let size = handshake.metadata_size.context("peer did not tell the metadata size")?;
+        anyhow::ensure!(
+            size > 0 && size <= MAX_METADATA_LEN,
+            "peer claims metadata of {size} bytes"
+        );
+
+        let npieces = size.div_ceil(PIECE_LEN);
+        let mut metadata = Vec::with_capacity(size);
+        for piece in 0..npieces {
+            let request = MetadataMessage {
+                msg_type: REQUEST,
+                piece,
+                total_size: None,
+            };
+            let request = serde_bencode::to_bytes(&request).context("encode metadata request")?;
+            peer.send_extended(remote_id, &request).await?;
+            let length = PIECE_LEN.min(size - piece * PIECE_LEN);
+            let data = loop {
+                let (id, payload) = recv_extended(&mut peer).await?;
+                if id == LOCAL_ID {
+                    break metadata_piece(payload, piece, length)?;
+                }
+            };
+            metadata.extend_from_slice(&data);
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&metadata).into();
+        anyhow::ensure!(hash == info_hash, "metadata does not match the info hash");
+        serde_bencode::from_bytes(&metadata).context("parse metadata")
+    })
+    .await
+    .context("fetching metadata timed out")?
+    .with_context(|| format!("fetch metadata from {addr}"))
+}
+
+/// The next extended message from `peer`, as its id and payload, skipping all other messages.
+async fn recv_extended(peer: &mut PeerConnection) -> anyhow::Result<(u8, Vec<u8>)> {
+    loop {
+        let message = peer.recv().await?;
+        if message.tag != MessageTag::Extended {
+            continue;
+        }
+        let (id, payload) = message.extended()?;
+        return Ok((id, payload.to_vec()));
+    }
+}
+
+/// The `length` bytes of metadata piece `piece` in a ut_metadata message.
+fn metadata_piece(mut payload: Vec<u8>, piece: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+    // The dictionary has no length prefix, so the data starts wherever it ends.
+    let split = bencode_len(&payload).context("parse metadata message")?;
+    let message: MetadataMessage =
+        serde_bencode::from_bytes(&payload[..split]).context("parse metadata message")?;
+    match message.msg_type {
+        DATA => {}
+        REJECT => anyhow::bail!("peer refused to send metadata piece {}", message.piece),
+        msg_type => anyhow::bail!("unexpected metadata message type {msg_type}"),
+    }
+    anyhow::ensure!(message.piece == piece, "peer sent metadata piece {} instead of {piece}", message.piece);
+    let data = payload.split_off(split);
+    anyhow::ensure!(
+        data.len() == length,
+        "metadata piece {piece} is {} bytes instead of {length}",
+        data.len()
+    );
+    Ok(data)
+}
+
+/// The length of the bencoded value at the start of `data`, if there is a well-formed one.
+fn bencode_len(data: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    // Lists and dictionaries we are inside of; they all end the same way.
+    let mut depth = 0usize;
+    loop {
+        match *data.get(pos)? {
+            b'i' => pos += data[pos..].iter().position(|&b| b == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                pos += 1;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = pos + data[pos..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&data[pos..colon]).ok()?.parse().ok()?;
+                pos = colon.checked_add(1 + len).filter(|&end| end <= data.len())?;
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(msg_type: u8, piece: usize, total_size: Option<usize>, data: &[u8]) -> Vec<u8> {
+        let header = MetadataMessage {
+            msg_type,
+            piece,
+            total_size,
+        };
+        let mut payload = serde_bencode::to_bytes(&header).unwrap();
+        payload.extend_from_slice(data);
+        payload
+    }
+
+    #[test]
+    fn splits_off_the_data_after_the_dictionary() {
+        // Metadata is bencoded itself, so the data looks much like the dictionary before it.
+        let data = b"d6:lengthi12e4:name4:teste";
+        let payload = message(DATA, 1, Some(PIECE_LEN + data.len()), data);
+        assert_eq!(metadata_piece(payload, 1, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn reports_rejections() {
+        let error = metadata_piece(message(REJECT, 2, None, b""), 2, PIECE_LEN).unwrap_err();
+        assert!(error.to_string().contains("refused"), "{error:#}");
+    }
+
+    #[test]
+    fn rejects_unexpected_messages() {
+        // A request instead of data.
+        assert!(metadata_piece(message(REQUEST, 0, None, b""), 0, 10).is_err());
+        // Some other piece than the one we asked for.
+        assert!(metadata_piece(message(DATA, 1, Some(20), &[0; 10]), 0, 10).is_err());
+        // Less data than the piece holds.
+        assert!(metadata_piece(message(DATA, 0, Some(20), &[0; 5]), 0, 10).is_err());
+        // More data than the piece holds.
+        assert!(metadata_piece(message(DATA, 0, Some(20), &[0; 15]), 0, 10).is_err());
+        assert!(metadata_piece(b"garbage".to_vec(), 0, 3).is_err());
+    }
+
+    #[test]
+    fn finds_the_end_of_nested_values() {
+        assert_eq!(bencode_len(b"d1:ai-1e1:bl3:xyzdeee rest"), Some(21));
+        assert_eq!(bencode_len(b"4:spam"), Some(6));
+        assert_eq!(bencode_len(b"d1:a5:abce"), None);
+        assert_eq!(bencode_len(b"ld"), None);
+        assert_eq!(bencode_len(b"x"), None);
+    }
+}
@@ -1,6 +1,38 @@
+use anyhow::Context;
 use bytes::{Buf, BufMut, BytesMut};
-use tokio_util::codec::Decoder;
-use tokio_util::codec::Encoder;
+use futures_util::{SinkExt, StreamExt};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// How long we wait for a TCP connection and handshake before giving up on a peer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Azureus-style client identifier and version (0.1.0) at the start of our peer ids.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-XX0100-";
+
+/// Byte and bit of the reserved handshake bytes that announce the extension protocol of BEP 10.
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+/// Byte and bit of the reserved handshake bytes that announce the fast extension of BEP 6.
+const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+
+/// A fresh peer id for one session: [`PEER_ID_PREFIX`] followed by random alphanumerics, so that
+/// concurrent instances stay apart in a swarm while trackers and peers can still tell our client.
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for (byte, random) in peer_id[8..].iter_mut().zip(rand::thread_rng().sample_iter(Alphanumeric)) {
+        *byte = random;
+    }
+    peer_id
+}
 
 #[repr(C)]
 pub struct Handshake {
@@ -13,15 +45,36 @@ pub struct Handshake {
 
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+        reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id
         }
     }
 
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_EXTENSION.0] & FAST_EXTENSION.1 != 0
+    }
+
+    /// Checks that a handshake we received is one of the BitTorrent protocol.
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.length == 19, "peer sent invalid handshake");
+        anyhow::ensure!(
+            &self.bittorrent == b"BitTorrent protocol",
+            "peer does not speak the BitTorrent protocol"
+        );
+        Ok(())
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
@@ -58,6 +111,13 @@ impl Request {
         u32::from_be_bytes(self.length)
     }
 
+    /// The request in the payload of a `Request`, `Cancel` or `Reject` message.
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        let payload: &[u8; 12] = payload.try_into().ok()?;
+        let field = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().expect("4 bytes"));
+        Some(Self::new(field(0), field(4), field(8)))
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
@@ -109,6 +169,15 @@ pub enum MessageTag {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    /// The fast extension messages of BEP 6 follow.
+    Suggest = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    /// A request the peer will not answer.
+    Reject = 16,
+    AllowedFast = 17,
+    /// A message of the extension protocol: an extended message id followed by its payload.
+    Extended = 20,
 }
 
 #[derive(Debug, Clone)]
@@ -117,6 +186,17 @@ pub struct Message {
     pub payload: Vec<u8>
 }
 
+impl Message {
+    /// The extended message id and payload of an [`Extended`](MessageTag::Extended) message.
+    pub fn extended(&self) -> anyhow::Result<(u8, &[u8])> {
+        let (&id, payload) = self
+            .payload
+            .split_first()
+            .context("extended message without an id")?;
+        Ok((id, payload))
+    }
+}
+
 pub struct MessageFramer;
 
 const MAX: usize = 1 << 16;
@@ -182,6 +262,13 @@ impl Decoder for MessageFramer {
             6 =>  MessageTag::Request,
             7 =>  MessageTag::Piece,
             8 =>  MessageTag::Cancel,
+            9 =>  MessageTag::Port,
+            13 => MessageTag::Suggest,
+            14 => MessageTag::HaveAll,
+            15 => MessageTag::HaveNone,
+            16 => MessageTag::Reject,
+            17 => MessageTag::AllowedFast,
+            20 => MessageTag::Extended,
             tag  =>  {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
@@ -212,7 +299,7 @@ impl Encoder<Message> for MessageFramer {
 
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_le_bytes(item.payload.len() as u32 + 1);
+        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
 
         // Reserve space in the buffer.
         dst.reserve(4 + 1 + item.payload.len());
@@ -225,3 +312,201 @@ impl Encoder<Message> for MessageFramer {
     }
 }
 
+
+/// Which pieces a peer claims to have, as sent in `Bitfield` and `Have` messages.
+#[derive(Debug, Clone)]
+pub struct Bitfield {
+    payload: Vec<u8>,
+    npieces: usize,
+}
+
+impl Bitfield {
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            payload: vec![0; npieces.div_ceil(8)],
+            npieces,
+        }
+    }
+
+    /// A bitfield with every piece set, as a `HaveAll` message stands for.
+    pub fn full(npieces: usize) -> Self {
+        let mut bitfield = Self::new(npieces);
+        for piece_i in 0..npieces {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
+
+    pub fn from_payload(mut payload: Vec<u8>, npieces: usize) -> Self {
+        payload.resize(npieces.div_ceil(8), 0);
+        Self { payload, npieces }
+    }
+
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        if piece_i >= self.npieces {
+            return false;
+        }
+        let byte = self.payload[piece_i / 8];
+        byte & (1 << (7 - piece_i % 8)) != 0
+    }
+
+    pub fn set_piece(&mut self, piece_i: usize) {
+        if piece_i < self.npieces {
+            self.payload[piece_i / 8] |= 1 << (7 - piece_i % 8);
+        }
+    }
+
+    pub fn clear_piece(&mut self, piece_i: usize) {
+        if piece_i < self.npieces {
+            self.payload[piece_i / 8] &= !(1 << (7 - piece_i % 8));
+        }
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.npieces).filter(|&piece_i| self.has_piece(piece_i))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+/// Extended message id of the extension handshake; the ids of all other extended messages are
+/// chosen by the receiving side in its handshake.
+pub const EXTENSION_HANDSHAKE: u8 = 0;
+
+/// The extension handshake of BEP 10: which extensions a peer supports, and under which extended
+/// message id it wants to receive the messages of each.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// Extension names to message ids; an id of 0 means the extension is not supported.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Size of the info dictionary in bytes, from BEP 9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    /// The message id the peer wants for `extension`, if it supports it.
+    pub fn id(&self, extension: &str) -> Option<u8> {
+        let id = *self.m.get(extension)?;
+        u8::try_from(id).ok().filter(|&id| id != EXTENSION_HANDSHAKE)
+    }
+}
+
+/// A framed connection to a single peer that has completed the handshake.
+pub struct PeerConnection {
+    addr: SocketAddr,
+    stream: Framed<TcpStream, MessageFramer>,
+    peer_id: [u8; 20],
+    extensions: bool,
+    fast: bool,
+}
+
+impl PeerConnection {
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> anyhow::Result<Self> {
+        let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .context("connect to peer timed out")?
+            .context("connect to peer")?;
+        let mut handshake = Handshake::new(info_hash, peer_id);
+        tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let handshake_bytes = handshake.as_bytes_mut();
+            stream.write_all(handshake_bytes).await.context("write handshake")?;
+            stream.read_exact(handshake_bytes).await.context("read handshake")?;
+            anyhow::Ok(())
+        })
+        .await
+        .context("handshake timed out")??;
+        handshake.check()?;
+        anyhow::ensure!(handshake.info_hash == info_hash, "peer is serving a different torrent");
+        Ok(Self::new(addr, stream, &handshake))
+    }
+
+    /// Completes the handshake on a connection the peer at `addr` opened. `torrent` gets the info
+    /// hash the peer asks for and returns the peer id to answer with, along with whatever the
+    /// caller wants back for the torrent, or `None` to turn the peer away.
+    pub async fn accept<T>(
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        torrent: impl FnOnce([u8; 20]) -> Option<([u8; 20], T)>,
+    ) -> anyhow::Result<(Self, T)> {
+        tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let mut handshake = Handshake::new([0; 20], [0; 20]);
+            stream
+                .read_exact(handshake.as_bytes_mut())
+                .await
+                .context("read handshake")?;
+            handshake.check()?;
+            let info_hash = handshake.info_hash;
+            let (peer_id, found) = torrent(info_hash).context("peer asked for a torrent we do not serve")?;
+            stream
+                .write_all(Handshake::new(info_hash, peer_id).as_bytes_mut())
+                .await
+                .context("write handshake")?;
+            Ok((Self::new(addr, stream, &handshake), found))
+        })
+        .await
+        .context("handshake timed out")?
+    }
+
+    fn new(addr: SocketAddr, stream: TcpStream, handshake: &Handshake) -> Self {
+        Self {
+            addr,
+            stream: Framed::new(stream, MessageFramer),
+            peer_id: handshake.peer_id,
+            extensions: handshake.supports_extensions(),
+            fast: handshake.supports_fast(),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// Whether the peer speaks the extension protocol of BEP 10.
+    pub fn supports_extensions(&self) -> bool {
+        self.extensions
+    }
+
+    /// Whether the peer speaks the fast extension of BEP 6.
+    pub fn supports_fast(&self) -> bool {
+        self.fast
+    }
+
+    /// Sends the extended message `id`, as the peer numbered it in its extension handshake.
+    pub async fn send_extended(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<()> {
+        let mut extended = Vec::with_capacity(1 + payload.len());
+        extended.push(id);
+        extended.extend_from_slice(payload);
+        self.send(Message {
+            tag: MessageTag::Extended,
+            payload: extended,
+        })
+        .await
+    }
+
+    pub async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        self.stream
+            .send(message)
+            .await
+            .with_context(|| format!("send message to {}", self.addr))
+    }
+
+    pub async fn recv(&mut self) -> anyhow::Result<Message> {
+        self.stream
+            .next()
+            .await
+            .context("peer closed the connection")?
+            .context("peer message was invalid")
+    }
+}
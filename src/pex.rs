@@ -0,0 +1,262 @@
+//! Peer exchange (ut_pex, BEP 11): connected peers telling each other whom else they are
+//! connected to, so that a download learns about the rest of the swarm from its first few peers.
+
+use crate::peer::ExtensionHandshake;
+use crate::tracker::{Peer, PeerAddr, Peers};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+pub const EXTENSION_NAME: &str = "ut_pex";
+
+/// The extended message id we ask peers to use for ut_pex messages to us.
+pub const LOCAL_ID: u8 = 2;
+
+/// How often we tell a peer what changed; BEP 11 allows no more than one message a minute.
+pub const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Messages that follow the previous one sooner than this are ignored. Less than
+/// [`INTERVAL`] to allow for timers that fire a little early.
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+/// Most peers one message may add, and separately drop.
+pub const MAX_PEERS: usize = 50;
+
+/// The peer prefers encrypted connections.
+pub const PREFERS_ENCRYPTION: u8 = 0x01;
+/// The peer has the whole torrent.
+pub const SEED: u8 = 0x02;
+/// The peer supports uTP.
+pub const UTP: u8 = 0x04;
+/// The peer supports the holepunch extension.
+pub const HOLEPUNCH: u8 = 0x08;
+/// The sender connected to the peer, so it accepts incoming connections.
+pub const REACHABLE: u8 = 0x10;
+
+/// A ut_pex message. Peer lists are in compact form, with one byte of flags per added peer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    pub added6_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Self {
+        let (added4, added6): (Vec<_>, Vec<_>) = added.iter().partition(|(addr, _)| addr.is_ipv4());
+        let added_peers = |added: &[&(SocketAddr, u8)]| peers(added.iter().map(|&&(addr, _)| addr));
+        let flags = |added: &[&(SocketAddr, u8)]| ByteBuf::from(added.iter().map(|&&(_, flags)| flags).collect::<Vec<_>>());
+        let dropped = peers(dropped.iter().copied());
+        Self {
+            added: ByteBuf::from(added_peers(&added4).to_compact()),
+            added_flags: flags(&added4),
+            added6: ByteBuf::from(added_peers(&added6).to_compact6()),
+            added6_flags: flags(&added6),
+            dropped: ByteBuf::from(dropped.to_compact()),
+            dropped6: ByteBuf::from(dropped.to_compact6()),
+        }
+    }
+
+    /// The added peers worth connecting to, with their flags. Addresses nobody can connect to
+    /// are left out, and so is everything past the first [`MAX_PEERS`].
+    pub fn added(&self) -> anyhow::Result<Vec<(SocketAddr, u8)>> {
+        let added = Peers::from_compact(&self.added).context("added peers are not in compact form")?;
+        let added6 = Peers::from_compact6(&self.added6).context("added6 peers are not in compact form")?;
+        anyhow::ensure!(
+            self.dropped.len().is_multiple_of(6) && self.dropped6.len().is_multiple_of(18),
+            "dropped peers are not in compact form"
+        );
+        // Flags that do not line up with their peers tell us nothing.
+        let flags = |peers: &Peers, flags: &ByteBuf| {
+            if flags.len() == peers.0.len() {
+                flags.to_vec()
+            } else {
+                vec![0; peers.0.len()]
+            }
+        };
+        let added_flags = flags(&added, &self.added_flags);
+        let added6_flags = flags(&added6, &self.added6_flags);
+        Ok(added
+            .0
+            .iter()
+            .zip(added_flags)
+            .chain(added6.0.iter().zip(added6_flags))
+            .filter_map(|(peer, flags)| match peer.addr {
+                PeerAddr::Ip(addr) if is_connectable(addr) => Some((addr, flags)),
+                _ => None,
+            })
+            .take(MAX_PEERS)
+            .collect())
+    }
+}
+
+fn peers(addrs: impl Iterator<Item = SocketAddr>) -> Peers {
+    Peers(
+        addrs
+            .map(|addr| Peer {
+                addr: PeerAddr::Ip(addr),
+                peer_id: None,
+            })
+            .collect(),
+    )
+}
+
+fn is_connectable(addr: SocketAddr) -> bool {
+    let unusable = match addr.ip() {
+        IpAddr::V4(ip) => ip.is_unspecified() || ip.is_multicast() || ip.is_broadcast(),
+        IpAddr::V6(ip) => ip.is_unspecified() || ip.is_multicast(),
+    };
+    addr.port() != 0 && !unusable
+}
+
+/// Our side of peer exchange with one peer.
+#[derive(Debug, Default)]
+pub struct PexSession {
+    /// The id the peer wants for ut_pex messages, once it told us it supports them.
+    remote_id: Option<u8>,
+    /// The peers we told the peer about and have not dropped since.
+    sent: HashSet<SocketAddr>,
+    next_send: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexSession {
+    /// Takes note of the peer's extension handshake. If it supports ut_pex, the first message
+    /// is due right away.
+    pub fn handshake(&mut self, handshake: &ExtensionHandshake) {
+        self.remote_id = handshake.id(EXTENSION_NAME);
+        self.next_send = self.remote_id.map(|_| Instant::now());
+    }
+
+    /// When to send the peer our next message, if it takes part in peer exchange.
+    pub fn next_send(&self) -> Option<Instant> {
+        self.next_send
+    }
+
+    /// The message id and message that tell `peer` how the `connected` peers, with their flags,
+    /// changed since our last message; `None` if nothing did. Schedules the next message.
+    pub fn update(&mut self, peer: SocketAddr, connected: &HashMap<SocketAddr, u8>) -> Option<(u8, PexMessage)> {
+        let remote_id = self.remote_id?;
+        self.next_send = Some(Instant::now() + INTERVAL);
+        let added: Vec<_> = connected
+            .iter()
+            .filter(|&(&addr, _)| addr != peer && !self.sent.contains(&addr))
+            .map(|(&addr, &flags)| (addr, flags))
+            .take(MAX_PEERS)
+            .collect();
+        let dropped: Vec<_> = self
+            .sent
+            .iter()
+            .filter(|addr| !connected.contains_key(addr))
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.sent.extend(added.iter().map(|&(addr, _)| addr));
+        Some((remote_id, PexMessage::new(&added, &dropped)))
+    }
+
+    /// The peers added by a ut_pex message from the peer, or none if the peer sends more often
+    /// than it may.
+    pub fn receive(&mut self, payload: &[u8]) -> anyhow::Result<Vec<(SocketAddr, u8)>> {
+        let now = Instant::now();
+        if self
+            .last_received
+            .is_some_and(|last| now.duration_since(last) < MIN_RECEIVE_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_received = Some(now);
+        let message: PexMessage = serde_bencode::from_bytes(payload).context("parse peer exchange message")?;
+        message.added()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn added_round_trips_with_flags() {
+        let added = [(addr("10.0.0.1:6881"), SEED | REACHABLE), (addr("[2001:db8::1]:51413"), UTP)];
+        let message = PexMessage::new(&added, &[addr("10.0.0.2:6881")]);
+        let encoded = serde_bencode::to_bytes(&message).unwrap();
+        let decoded: PexMessage = serde_bencode::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded.added().unwrap(), added);
+        assert_eq!(decoded.dropped.len(), 6);
+    }
+
+    #[test]
+    fn added_rejects_lists_not_in_compact_form() {
+        for message in [
+            PexMessage {
+                added: ByteBuf::from(vec![0; 7]),
+                ..PexMessage::default()
+            },
+            PexMessage {
+                added6: ByteBuf::from(vec![0; 17]),
+                ..PexMessage::default()
+            },
+            PexMessage {
+                dropped: ByteBuf::from(vec![0; 5]),
+                ..PexMessage::default()
+            },
+            PexMessage {
+                dropped6: ByteBuf::from(vec![0; 19]),
+                ..PexMessage::default()
+            },
+        ] {
+            assert!(message.added().is_err());
+        }
+    }
+
+    #[test]
+    fn added_ignores_flags_that_do_not_line_up() {
+        let mut message = PexMessage::new(&[(addr("10.0.0.1:6881"), SEED), (addr("10.0.0.2:6881"), SEED)], &[]);
+        message.added_flags = ByteBuf::from(vec![SEED]);
+        assert_eq!(message.added().unwrap(), [(addr("10.0.0.1:6881"), 0), (addr("10.0.0.2:6881"), 0)]);
+    }
+
+    #[test]
+    fn added_leaves_out_unconnectable_peers() {
+        let added = [
+            (addr("0.0.0.0:6881"), 0),
+            (addr("10.0.0.1:0"), 0),
+            (addr("224.0.0.1:6881"), 0),
+            (addr("255.255.255.255:6881"), 0),
+            (addr("[::]:6881"), 0),
+            (addr("[ff02::1]:6881"), 0),
+            (addr("10.0.0.1:6881"), 0),
+        ];
+        assert_eq!(PexMessage::new(&added, &[]).added().unwrap(), [(addr("10.0.0.1:6881"), 0)]);
+    }
+
+    #[test]
+    fn added_takes_at_most_max_peers() {
+        let added: Vec<_> = (1..=MAX_PEERS as u16 + 10)
+            .map(|port| (SocketAddr::from(([10, 0, 0, 1], port)), 0))
+            .collect();
+        assert_eq!(PexMessage::new(&added, &[]).added().unwrap(), added[..MAX_PEERS]);
+    }
+}
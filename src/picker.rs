@@ -0,0 +1,323 @@
+use crate::peer::Bitfield;
+use rand::Rng;
+use std::time::Duration;
+
+/// What the download engine knows about a piece when it asks a picker for work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    /// The piece is not part of this download.
+    Unwanted,
+    /// No block of the piece has been requested yet.
+    Missing,
+    /// Some blocks have been requested or received and others are still open.
+    Partial,
+    /// Every block of the piece is in flight or received.
+    Requested,
+    /// The piece has been downloaded and verified.
+    Complete,
+}
+
+/// Decides which piece a peer should work on next.
+///
+/// The engine reports every bitfield, `Have` and disconnect so that implementations can keep
+/// track of how many peers have each piece. Only pieces whose status is [`PieceStatus::Missing`]
+/// or [`PieceStatus::Partial`] and which the peer has may be returned from [`pick`].
+///
+/// [`pick`]: PiecePicker::pick
+pub trait PiecePicker: Send {
+    fn peer_added(&mut self, bitfield: &Bitfield);
+
+    fn peer_has(&mut self, piece_i: usize);
+
+    fn peer_removed(&mut self, bitfield: &Bitfield);
+
+    fn pick(&mut self, bitfield: &Bitfield, status: &[PieceStatus]) -> Option<usize>;
+
+    /// How long a block of `piece_i` may stay unanswered before the engine also requests it from
+    /// another peer. `None`, the default, means requests are never duplicated this way.
+    fn deadline(&self, _piece_i: usize, _status: &[PieceStatus]) -> Option<Duration> {
+        None
+    }
+}
+
+/// Number of connected peers that have each piece.
+#[derive(Debug, Clone, Default)]
+pub struct Availability {
+    counts: Vec<u32>,
+}
+
+impl Availability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, piece_i: usize) -> u32 {
+        self.counts.get(piece_i).copied().unwrap_or(0)
+    }
+
+    pub fn add(&mut self, piece_i: usize) {
+        if piece_i >= self.counts.len() {
+            self.counts.resize(piece_i + 1, 0);
+        }
+        self.counts[piece_i] += 1;
+    }
+
+    pub fn remove(&mut self, piece_i: usize) {
+        if let Some(count) = self.counts.get_mut(piece_i) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for piece_i in bitfield.pieces() {
+            self.add(piece_i);
+        }
+    }
+
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for piece_i in bitfield.pieces() {
+            self.remove(piece_i);
+        }
+    }
+
+    /// Returns the piece among `candidates` held by the fewest peers, breaking ties uniformly at
+    /// random.
+    pub fn rarest(&self, candidates: impl Iterator<Item = usize>) -> Option<usize> {
+        let mut rng = rand::thread_rng();
+        let mut best = None;
+        let mut ties = 0;
+        for piece_i in candidates {
+            let count = self.get(piece_i);
+            match best {
+                Some((_, best_count)) if count > best_count => {}
+                Some((_, best_count)) if count == best_count => {
+                    ties += 1;
+                    if rng.gen_range(0..ties) == 0 {
+                        best = Some((piece_i, count));
+                    }
+                }
+                _ => {
+                    best = Some((piece_i, count));
+                    ties = 1;
+                }
+            }
+        }
+        best.map(|(piece_i, _)| piece_i)
+    }
+}
+
+/// Picks the piece the fewest peers have, so that rare pieces spread through the swarm before
+/// their holders leave. Pieces that are already underway are always finished first.
+#[derive(Debug, Clone, Default)]
+pub struct RarestFirst {
+    availability: Availability,
+}
+
+impl RarestFirst {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn peer_added(&mut self, bitfield: &Bitfield) {
+        self.availability.add_bitfield(bitfield);
+    }
+
+    fn peer_has(&mut self, piece_i: usize) {
+        self.availability.add(piece_i);
+    }
+
+    fn peer_removed(&mut self, bitfield: &Bitfield) {
+        self.availability.remove_bitfield(bitfield);
+    }
+
+    fn pick(&mut self, bitfield: &Bitfield, status: &[PieceStatus]) -> Option<usize> {
+        let with_status = |wanted| {
+            bitfield
+                .pieces()
+                .filter(move |&piece_i| status[piece_i] == wanted)
+        };
+        self.availability
+            .rarest(with_status(PieceStatus::Partial))
+            .or_else(|| self.availability.rarest(with_status(PieceStatus::Missing)))
+    }
+}
+
+/// Downloads pieces roughly in order for streaming playback.
+///
+/// The first `window` unfinished pieces from the playback position form a high-priority window
+/// that is always fetched in order, and blocks of window pieces get deadlines that grow with the
+/// distance from the playback position. Outside the window pieces are picked rarest-first so that
+/// the swarm still benefits from what we download.
+#[derive(Debug, Clone)]
+pub struct Sequential {
+    window: usize,
+    deadline: Duration,
+    rest: RarestFirst,
+}
+
+impl Sequential {
+    pub const DEFAULT_WINDOW: usize = 8;
+    pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(2);
+
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            deadline: Self::DEFAULT_DEADLINE,
+            rest: RarestFirst::new(),
+        }
+    }
+
+    /// Sets the deadline of the piece at the playback position; the n-th piece of the window gets
+    /// `n` times this.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Wanted pieces that are not yet complete, starting at the playback position.
+    fn window<'s>(&self, status: &'s [PieceStatus]) -> impl Iterator<Item = usize> + 's {
+        status
+            .iter()
+            .enumerate()
+            .filter(|(_, &status)| !matches!(status, PieceStatus::Unwanted | PieceStatus::Complete))
+            .map(|(piece_i, _)| piece_i)
+            .take(self.window)
+    }
+}
+
+impl Default for Sequential {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_WINDOW)
+    }
+}
+
+impl PiecePicker for Sequential {
+    fn peer_added(&mut self, bitfield: &Bitfield) {
+        self.rest.peer_added(bitfield);
+    }
+
+    fn peer_has(&mut self, piece_i: usize) {
+        self.rest.peer_has(piece_i);
+    }
+
+    fn peer_removed(&mut self, bitfield: &Bitfield) {
+        self.rest.peer_removed(bitfield);
+    }
+
+    fn pick(&mut self, bitfield: &Bitfield, status: &[PieceStatus]) -> Option<usize> {
+        let pickable = |piece_i: usize| {
+            bitfield.has_piece(piece_i)
+                && matches!(status[piece_i], PieceStatus::Missing | PieceStatus::Partial)
+        };
+        if let Some(piece_i) = self.window(status).find(|&piece_i| pickable(piece_i)) {
+            return Some(piece_i);
+        }
+        self.rest.pick(bitfield, status)
+    }
+
+    fn deadline(&self, piece_i: usize, status: &[PieceStatus]) -> Option<Duration> {
+        let position = self.window(status).position(|window_i| window_i == piece_i)?;
+        Some(self.deadline * (position as u32 + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PieceStatus::*;
+
+    fn bitfield(npieces: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(npieces);
+        for &piece_i in pieces {
+            bitfield.set_piece(piece_i);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn availability_counts_peers() {
+        let mut availability = Availability::new();
+        availability.add_bitfield(&bitfield(8, &[0, 3]));
+        availability.add_bitfield(&bitfield(8, &[3]));
+        availability.add(7);
+        assert_eq!((0..8).map(|piece_i| availability.get(piece_i)).collect::<Vec<_>>(), [1, 0, 0, 2, 0, 0, 0, 1]);
+        availability.remove_bitfield(&bitfield(8, &[0, 3]));
+        availability.remove(0);
+        assert_eq!((availability.get(0), availability.get(3)), (0, 1));
+    }
+
+    #[test]
+    fn rarest_first_picks_the_rarest_piece_the_peer_has() {
+        let mut picker = RarestFirst::new();
+        picker.peer_added(&bitfield(4, &[0, 1, 2, 3]));
+        picker.peer_added(&bitfield(4, &[0, 1, 3]));
+        picker.peer_has(1);
+        let status = [Missing; 4];
+        assert_eq!(picker.pick(&bitfield(4, &[0, 1, 2, 3]), &status), Some(2));
+        // The peer does not have piece 2; 0 and 3 are the rarest of the rest.
+        let pick = picker.pick(&bitfield(4, &[0, 1, 3]), &status);
+        assert!(matches!(pick, Some(0 | 3)), "{pick:?}");
+        assert_eq!(picker.pick(&bitfield(4, &[]), &status), None);
+    }
+
+    #[test]
+    fn rarest_first_finishes_partial_pieces_first() {
+        let mut picker = RarestFirst::new();
+        picker.peer_added(&bitfield(4, &[0, 1, 2, 3]));
+        picker.peer_added(&bitfield(4, &[3]));
+        let status = [Missing, Complete, Missing, Partial];
+        assert_eq!(picker.pick(&bitfield(4, &[0, 1, 2, 3]), &status), Some(3));
+    }
+
+    #[test]
+    fn rarest_first_only_picks_open_pieces() {
+        let mut picker = RarestFirst::new();
+        picker.peer_added(&bitfield(4, &[0, 1, 2, 3]));
+        let status = [Unwanted, Requested, Complete, Missing];
+        assert_eq!(picker.pick(&bitfield(4, &[0, 1, 2, 3]), &status), Some(3));
+        assert_eq!(picker.pick(&bitfield(4, &[0, 1, 2]), &status), None);
+    }
+
+    #[test]
+    fn rarest_breaks_ties_at_random() {
+        let availability = Availability::new();
+        let mut seen = [false; 3];
+        for _ in 0..200 {
+            seen[availability.rarest(0..3).unwrap()] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+
+    #[test]
+    fn sequential_fetches_the_window_in_order() {
+        let mut picker = Sequential::new(3);
+        // Piece 4 is the rarest, but outside the window.
+        picker.peer_added(&bitfield(6, &[0, 1, 2, 3, 5]));
+        picker.peer_added(&bitfield(6, &[0, 1, 2, 3, 4, 5]));
+        let all = bitfield(6, &[0, 1, 2, 3, 4, 5]);
+        let status = [Complete, Requested, Missing, Missing, Missing, Missing];
+        assert_eq!(picker.pick(&all, &status), Some(2));
+        // Window pieces the peer lacks are skipped for the ones it has.
+        assert_eq!(picker.pick(&bitfield(6, &[3, 4]), &status), Some(3));
+    }
+
+    #[test]
+    fn sequential_falls_back_to_rarest_first_outside_the_window() {
+        let mut picker = Sequential::new(2);
+        picker.peer_added(&bitfield(6, &[0, 1, 2, 3, 5]));
+        picker.peer_added(&bitfield(6, &[0, 1, 2, 3, 4, 5]));
+        let status = [Unwanted, Requested, Requested, Missing, Missing, Missing];
+        assert_eq!(picker.pick(&bitfield(6, &[0, 1, 2, 3, 4, 5]), &status), Some(4));
+    }
+
+    #[test]
+    fn sequential_deadlines_grow_through_the_window() {
+        let picker = Sequential::new(3).with_deadline(Duration::from_secs(1));
+        let status = [Complete, Unwanted, Partial, Missing, Requested, Missing];
+        let deadlines: Vec<_> = (0..6).map(|piece_i| picker.deadline(piece_i, &status)).collect();
+        let secs = |secs| Some(Duration::from_secs(secs));
+        assert_eq!(deadlines, [None, None, secs(1), secs(2), secs(3), None]);
+    }
+}
@@ -0,0 +1,83 @@
+//! The files of a torrent on disk, read as the one run of bytes its pieces are cut from.
+
+use crate::peer::Bitfield;
+use crate::torrent::{Keys, Torrent};
+use sha1::{Digest, Sha1};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// The data of a torrent under some path. Files that are missing or shorter than the torrent says
+/// read as zeros, so that the pieces they are part of merely fail their hash check.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    /// Every file of the torrent in order, with its length.
+    files: Vec<(PathBuf, usize)>,
+}
+
+impl Storage {
+    /// The files of `t` at `data`: the file itself for a single-file torrent, or the directory
+    /// holding the files of a multi-file one.
+    pub fn new(t: &Torrent, data: &Path) -> Self {
+        let files = match &t.info.keys {
+            Keys::SingleFile { length } => vec![(data.to_path_buf(), *length)],
+            Keys::MultipleFile { files } => files
+                .iter()
+                .map(|file| (data.join(file.path.iter().collect::<PathBuf>()), file.length))
+                .collect(),
+        };
+        Self { files }
+    }
+
+    /// Reads `length` bytes at `offset` into the torrent, across as many files as they span.
+    pub async fn read(&self, offset: usize, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0; length];
+        let end = offset + length;
+        let mut file_start = 0;
+        for (path, file_length) in &self.files {
+            let file_end = file_start + file_length;
+            let (start, stop) = (offset.max(file_start), end.min(file_end));
+            if start < stop {
+                read_at(path, start - file_start, &mut buf[start - offset..stop - offset]).await?;
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        Ok(buf)
+    }
+
+    /// Checks every piece of `t` against its hash, reading one piece at a time. Returns the
+    /// pieces that match.
+    pub async fn verify(&self, t: &Torrent) -> std::io::Result<Bitfield> {
+        let npieces = t.info.pieces.0.len();
+        let mut have = Bitfield::new(npieces);
+        for piece_i in 0..npieces {
+            let piece = self.read(piece_i * t.info.piece_length, t.piece_length(piece_i)).await?;
+            let hash: [u8; 20] = Sha1::digest(&piece).into();
+            if hash == t.info.pieces.0[piece_i] {
+                have.set_piece(piece_i);
+            }
+        }
+        Ok(have)
+    }
+}
+
+/// Fills `buf` from `path` starting at `offset`, leaving whatever the file does not have as is.
+async fn read_at(path: &Path, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+    let mut file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    file.seek(SeekFrom::Start(offset as u64)).await?;
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(())
+}
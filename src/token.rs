@@ -0,0 +1,80 @@
+//! Tokens that prove whoever presents them received them at their address, without our having to
+//! remember the ones we handed out: a token is a keyed hash of the address and the current time
+//! window.
+
+use sha1::{Digest, Sha1};
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub struct WindowedToken {
+    secret: [u8; 20],
+    lifetime: Duration,
+}
+
+impl WindowedToken {
+    /// Tokens stay valid for at least `lifetime` and at most twice as long.
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            secret: rand::random(),
+            lifetime,
+        }
+    }
+
+    fn window(&self, at: SystemTime) -> u64 {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.as_secs() / self.lifetime.as_secs().max(1)
+    }
+
+    fn token(&self, addr: &impl Display, window: u64) -> [u8; 8] {
+        let mut hasher = Sha1::new();
+        hasher.update(self.secret);
+        hasher.update(addr.to_string());
+        hasher.update(window.to_be_bytes());
+        let hash = hasher.finalize();
+        hash[..8].try_into().expect("sha1 is 20 bytes")
+    }
+
+    pub fn issue(&self, addr: impl Display) -> [u8; 8] {
+        self.issue_at(addr, SystemTime::now())
+    }
+
+    /// The token we handed out, or would have, to `addr` at time `at`.
+    pub fn issue_at(&self, addr: impl Display, at: SystemTime) -> [u8; 8] {
+        self.token(&addr, self.window(at))
+    }
+
+    /// Tokens from the previous window are still accepted, so that one handed out right before a
+    /// window ends lives for its whole lifetime too.
+    pub fn is_valid(&self, addr: impl Display, token: &[u8]) -> bool {
+        let window = self.window(SystemTime::now());
+        token == self.token(&addr, window) || token == self.token(&addr, window.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const LIFETIME: Duration = Duration::from_secs(60);
+    const ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn tokens_are_bound_to_their_address() {
+        let tokens = WindowedToken::new(LIFETIME);
+        let token = tokens.issue(ADDR);
+        assert!(tokens.is_valid(ADDR, &token));
+        assert!(!tokens.is_valid(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), &token));
+        assert!(!WindowedToken::new(LIFETIME).is_valid(ADDR, &token));
+    }
+
+    #[test]
+    fn tokens_outlive_their_window_once() {
+        let tokens = WindowedToken::new(LIFETIME);
+        let previous = tokens.issue_at(ADDR, SystemTime::now() - LIFETIME);
+        assert!(tokens.is_valid(ADDR, &previous));
+        let expired = tokens.issue_at(ADDR, SystemTime::now() - 2 * LIFETIME);
+        assert!(!tokens.is_valid(ADDR, &expired));
+    }
+}
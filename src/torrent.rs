@@ -1,47 +1,126 @@
 use serde::{Deserialize, Serialize};
 pub use hashes::Hashes;
 use anyhow::Context;
+use serde_bencode::value::Value;
 use sha1::{Sha1, Digest};
-use crate::download;
+use std::path::Path;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
-    pub announce: String,
-    pub info: Info
+    /// Left out by torrents that only list their trackers in `announce-list`, or have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+
+    /// Tiers of tracker urls from BEP 12.
+    #[serde(rename = "announce-list", default, skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    /// DHT nodes to bootstrap from, as host and port, for torrents meant to do without trackers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<(String, u16)>>,
+
+    pub info: Info,
+
+    /// The hash of the info dictionary as it was read. Dictionaries may have keys [`Info`] does
+    /// not know, so encoding it again does not necessarily give the same bytes.
+    #[serde(skip)]
+    info_hash: Option<[u8; 20]>,
 }
 
 impl Torrent {
     pub async fn read(file: impl AsRef<Path>) -> anyhow::Result<Self> {
         let f = tokio::fs::read(file).await.context("open torrent file")?;
-        let t: Torrent = serde_bencode::from_bytes(&f).context("parse torrent file")?;
+        Self::from_bytes(&f)
+    }
+
+    /// Parses a torrent file, hashing its info dictionary with every key it has.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut t: Torrent = serde_bencode::from_bytes(bytes).context("parse torrent file")?;
+        let Value::Dict(mut torrent) = serde_bencode::from_bytes(bytes).context("parse torrent file")? else {
+            anyhow::bail!("torrent file is not a dictionary");
+        };
+        let info = torrent.remove(&b"info"[..]).context("torrent file has no info dictionary")?;
+        let info = serde_bencode::to_bytes(&info).context("encode info dictionary")?;
+        t.info_hash = Some(Sha1::digest(info).into());
         Ok(t)
     }
 
+    /// A torrent without trackers for the info dictionary of `info_hash`, fetched from peers and
+    /// already checked against the hash.
+    pub fn from_metadata(info: Info, info_hash: [u8; 20]) -> Self {
+        Self {
+            announce: None,
+            announce_list: None,
+            nodes: None,
+            info,
+            info_hash: Some(info_hash),
+        }
+    }
+
     pub fn info_hash(&self) -> [u8; 20] {
+        if let Some(info_hash) = self.info_hash {
+            return info_hash;
+        }
         let encoded_info = serde_bencode::to_bytes(&self.info).expect("reencode info");
         let mut hasher = Sha1::new();
         hasher.update(&encoded_info);
         hasher
             .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]")
+            .into()
+    }
+
+    /// Tracker urls by tier; per BEP 12 `announce` only counts when there is no `announce-list`.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        match (&self.announce_list, &self.announce) {
+            (Some(tiers), _) if tiers.iter().any(|tier| !tier.is_empty()) => tiers
+                .iter()
+                .filter(|tier| !tier.is_empty())
+                .cloned()
+                .collect(),
+            (_, Some(announce)) => vec![vec![announce.clone()]],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether peers must not be looked for anywhere but at the trackers of the torrent.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// Total number of bytes across all files in the torrent.
+    pub fn length(&self) -> usize {
+        match &self.info.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultipleFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Length of the piece at `piece_i`; only the last piece may be shorter.
+    pub fn piece_length(&self, piece_i: usize) -> usize {
+        let npieces = self.info.pieces.0.len();
+        if piece_i == npieces - 1 {
+            let md = self.length() % self.info.piece_length;
+            if md == 0 {
+                self.info.piece_length
+            } else {
+                md
+            }
+        } else {
+            self.info.piece_length
+        }
     }
 
     pub fn print_tree(&self) {
-        match self.info.keys {
+        match &self.info.keys {
             Keys::SingleFile { .. } => {
-                println!("{}", self.name);              
-            } 
+                println!("{}", self.info.name);
+            }
             Keys::MultipleFile { files } => {
                 for file in files {
-                    println!("{}", file.path);
+                    println!("{}", file.path.join(std::path::MAIN_SEPARATOR_STR));
                 }
             }
-        } 
-    }
-
-    pub async fn download_all(&self) -> anyhow::Result<Downloaded>{
-        download::all()
+        }
     }
 }
 
@@ -54,6 +133,10 @@ pub struct Info {
 
     pub pieces: Hashes,
 
+    /// Set to 1 by torrents whose peers may only come from their trackers, per BEP 27.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+
     #[serde(flatten)]
     pub keys: Keys,
 }
@@ -65,14 +148,14 @@ pub enum Keys {
         length: usize
     },
     MultipleFile {
-        files: File
+        files: Vec<File>
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
-    length: usize,
-    path: Vec<String>
+    pub length: usize,
+    pub path: Vec<String>
 }
 
 mod hashes {
@@ -95,13 +178,13 @@ mod hashes {
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
             where
                 E: de::Error, {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
-            } 
+            }
             Ok(
                 Hashes(
                     v.chunks_exact(20)
-                        .map(|slice_20| (slice_20.try_into().expect("length shouls be 20")))
+                        .map(|slice_20| slice_20.try_into().expect("length shouls be 20"))
                         .collect()
                 )
             )
@@ -1,44 +1,616 @@
+use futures_util::future::BoxFuture;
+use reqwest::Url;
 use serde::{Serialize, Deserialize};
-pub use peers::Peers;
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+pub use announcer::Announcer;
+pub use peers::{Peer, PeerAddr, Peers};
+pub use retry::{Tracker, TrackerHealth};
+pub use udp::UdpTracker;
 
-#[derive(Debug, Clone, Serialize)]
+mod announcer;
+mod retry;
+pub mod server;
+mod udp;
+
+/// How long we wait for a tracker to answer an announce.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long we wait for the TCP connection to an HTTP tracker.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Redirects an HTTP tracker may send us through before we give up.
+const MAX_REDIRECTS: usize = 5;
+
+/// Immediate retries of a failed HTTP request before [`client`] reports the failure.
+const CLIENT_RETRIES: u32 = 3;
+
+/// Retransmissions of a UDP request before [`client`] gives up, well short of the protocol's
+/// maximum so that a dead tracker does not hold up a command for hours.
+const CLIENT_RETRANSMITS: u32 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("invalid tracker url {0:?}")]
+    InvalidUrl(String),
+    #[error("unsupported tracker url scheme {0:?}")]
+    UnsupportedScheme(String),
+    #[error("could not encode tracker request")]
+    Encode(#[from] serde_urlencoded::ser::Error),
+    #[error("tracker request failed")]
+    Http(#[from] reqwest::Error),
+    #[error("tracker answered with http status {0}")]
+    Status(reqwest::StatusCode),
+    #[error("tracker response is malformed")]
+    Decode(#[from] serde_bencode::Error),
+    #[error("tracker i/o failed")]
+    Io(#[from] std::io::Error),
+    #[error("tracker did not answer in time")]
+    Timeout,
+    #[error("tracker broke protocol: {0}")]
+    Protocol(String),
+    #[error("tracker refused the request: {0}")]
+    Failure(String),
+    #[error("tracker url {0:?} does not follow the scrape convention")]
+    ScrapeUnsupported(String),
+    #[error("torrent has no usable tracker")]
+    NoTrackers,
+}
+
+impl TrackerError {
+    /// Whether the same request might well succeed if we try again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TrackerError::Http(e) => !e.is_redirect() && !e.is_builder() && !e.is_decode(),
+            TrackerError::Status(status) => {
+                status.is_server_error()
+                    || *status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            TrackerError::Io(_) | TrackerError::Timeout => true,
+            _ => false,
+        }
+    }
+}
+
+/// Something that can tell us about the peers of a torrent.
+pub trait TrackerClient: Send + Sync {
+    fn announce<'a>(
+        &'a self,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, TrackerError>>;
+
+    /// Asks for the swarm statistics of each of `info_hashes`, in the same order, without
+    /// joining any of the swarms.
+    fn scrape<'a>(
+        &'a self,
+        info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<Vec<ScrapeStats>, TrackerError>>;
+}
+
+/// Returns a client for the tracker behind the `announce` url of a torrent.
+pub fn client(announce: &str) -> Result<Tracker, TrackerError> {
+    let url = Url::parse(announce).map_err(|_| TrackerError::InvalidUrl(announce.to_string()))?;
+    match url.scheme() {
+        "http" | "https" => Ok(Tracker::new(
+            announce,
+            Box::new(HttpTracker::new(url, DEFAULT_TIMEOUT)?),
+            CLIENT_RETRIES,
+        )),
+        // UDP requests are already retransmitted with back-off.
+        "udp" => Ok(Tracker::new(
+            announce,
+            Box::new(UdpTracker::new(&url, DEFAULT_TIMEOUT)?.with_retransmits(CLIENT_RETRANSMITS)),
+            0,
+        )),
+        scheme => Err(TrackerError::UnsupportedScheme(scheme.to_string())),
+    }
+}
+
+/// Returns a client for every distinct tracker in `tiers`, in tier order.
+///
+/// Trackers we cannot talk to are left out, so a torrent only fails here if it has none we can.
+pub fn clients(tiers: &[Vec<String>]) -> Result<Vec<Tracker>, TrackerError> {
+    Ok(clients_by_tier(tiers)?.into_iter().flatten().collect())
+}
+
+/// Like [`clients`], but keeps the trackers in their tiers; tiers left without any are dropped.
+pub fn clients_by_tier(tiers: &[Vec<String>]) -> Result<Vec<Vec<Tracker>>, TrackerError> {
+    let mut seen = HashSet::new();
+    let mut trackers = Vec::new();
+    let mut unusable = None;
+    for tier in tiers {
+        let mut clients = Vec::new();
+        for announce in tier {
+            if !seen.insert(announce.as_str()) {
+                continue;
+            }
+            match client(announce) {
+                Ok(tracker) => clients.push(tracker),
+                Err(e) => unusable = unusable.or(Some(e)),
+            }
+        }
+        if !clients.is_empty() {
+            trackers.push(clients);
+        }
+    }
+    match unusable {
+        Some(e) if trackers.is_empty() => Err(e),
+        _ if trackers.is_empty() => Err(TrackerError::NoTrackers),
+        _ => Ok(trackers),
+    }
+}
+
+/// A tracker that speaks the announce protocol over HTTP(S).
+#[derive(Debug, Clone)]
+pub struct HttpTracker {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl HttpTracker {
+    /// `timeout` covers the whole request, from connecting to reading the last byte.
+    pub fn new(url: Url, timeout: Duration) -> Result<Self, TrackerError> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS))
+            .gzip(true)
+            .build()?;
+        Ok(Self { url, client })
+    }
+
+    /// The announce url with the request parameters appended to any query it already has.
+    fn announce_url(&self, info_hash: [u8; 20], request: &TrackerRequest) -> Result<Url, TrackerError> {
+        let params = format!(
+            "{}&info_hash={}&peer_id={}",
+            serde_urlencoded::to_string(request)?,
+            urlencode(&info_hash),
+            urlencode(&request.peer_id)
+        );
+        let mut url = self.url.clone();
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{existing}&{params}"),
+            _ => params,
+        };
+        url.set_query(Some(&query));
+        Ok(url)
+    }
+
+    /// By convention, a tracker whose announce url ends in a path segment starting with
+    /// `announce` answers scrapes at the same url with that word replaced by `scrape`.
+    fn scrape_url(&self, info_hashes: &[[u8; 20]]) -> Result<Url, TrackerError> {
+        let unsupported = || TrackerError::ScrapeUnsupported(self.url.to_string());
+        let path = self.url.path();
+        let (dir, last) = path.rsplit_once('/').ok_or_else(unsupported)?;
+        let rest = last.strip_prefix("announce").ok_or_else(unsupported)?;
+        let mut url = self.url.clone();
+        url.set_path(&format!("{dir}/scrape{rest}"));
+
+        let params = info_hashes
+            .iter()
+            .map(|info_hash| format!("info_hash={}", urlencode(info_hash)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{existing}&{params}"),
+            _ => params,
+        };
+        url.set_query(Some(&query));
+        Ok(url)
+    }
+
+    /// Fetches `url` and returns the body of a successful response.
+    async fn get(&self, url: Url) -> Result<bytes::Bytes, TrackerError> {
+        // The url carries the whole query, which makes for unreadable errors.
+        let response = self.client.get(url).send().await.map_err(|e| e.without_url())?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| e.without_url())?;
+        // Trackers report errors in a dictionary of their own, sometimes with an error status.
+        if let Ok(failure) = serde_bencode::from_bytes::<Failure>(&body) {
+            return Err(TrackerError::Failure(failure.reason));
+        }
+        if !status.is_success() {
+            return Err(TrackerError::Status(status));
+        }
+        Ok(body)
+    }
+}
+
+impl TrackerClient for HttpTracker {
+    fn announce<'a>(
+        &'a self,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, TrackerError>> {
+        Box::pin(async move {
+            let body = self.get(self.announce_url(info_hash, request)?).await?;
+            Ok(serde_bencode::from_bytes(&body)?)
+        })
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<Vec<ScrapeStats>, TrackerError>> {
+        Box::pin(async move {
+            let body = self.get(self.scrape_url(info_hashes)?).await?;
+            let response: ScrapeResponse = serde_bencode::from_bytes(&body)?;
+            // Trackers leave out torrents they do not know, which is as good as an empty swarm.
+            Ok(info_hashes
+                .iter()
+                .map(|info_hash| {
+                    response
+                        .files
+                        .get(serde_bytes::Bytes::new(info_hash))
+                        .copied()
+                        .unwrap_or_default()
+                })
+                .collect())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackerRequest {
     // pub info_hash: [u8; 20],
-    pub peer_id: String,
+    /// Raw bytes, so it is percent-encoded by hand like the info hash.
+    #[serde(skip)]
+    pub peer_id: [u8; 20],
     pub port: u16,
     pub uploaded: usize,
     pub downloaded: usize,
     pub left: usize,
-    pub compact: u8
+    /// 1 for the compact peer list, 0 for the dictionary model.
+    #[serde(default)]
+    pub compact: u8,
+    /// Stays the same across a session so that the tracker recognizes us even if our address
+    /// changes; unlike the peer id it is never shown to other peers.
+    #[serde(default, with = "key")]
+    pub key: u32,
+    /// How many peers we would like; the tracker decides when this is left out.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub numwant: Option<usize>,
+    /// Our global IPv6 address, so that an IPv4 tracker can hand it out to IPv6 peers (BEP 7).
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ipv6: Option<Ipv6Addr>,
+    /// Left out for the regular announces in between events.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub event: Option<Event>,
+    /// The `tracker id` of the tracker's previous response, if it sent one.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub trackerid: Option<String>,
+}
+
+/// Milestones of a download that a tracker wants to hear about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first announce of a download.
+    Started,
+    /// We are shutting down gracefully.
+    Stopped,
+    /// The download finished; not sent if we already had everything when we started.
+    Completed,
 }
 
 impl TrackerRequest {
-    pub fn new(left: usize) -> Self {
+    /// A request with a random [`key`](TrackerRequest::key) for a session that uses `peer_id` and
+    /// accepts connections on `port`, or on none if it is 0, telling trackers we can also be
+    /// reached at `ipv6`; see [`local_ipv6`].
+    pub fn new(peer_id: [u8; 20], port: u16, left: usize, ipv6: Option<Ipv6Addr>) -> Self {
         Self {
-            peer_id: "00112233445566778899".to_string(),
-            port: 6881,
+            peer_id,
+            port,
             uploaded: 0,
             downloaded: 0,
             left,
-            compact: 1
+            compact: 1,
+            key: rand::random(),
+            numwant: None,
+            ipv6,
+            event: None,
+            trackerid: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The announce key goes over HTTP as eight hex digits, like most clients send it.
+mod key {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(key: &u32, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{key:08x}"))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u32, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let key = String::deserialize(deserializer)?;
+        // Some clients pick keys of their own format; all a tracker does with them is compare.
+        Ok(u32::from_str_radix(&key, 16).unwrap_or_else(|_| {
+            key.bytes()
+                .fold(0u32, |hash, byte| hash.rotate_left(5) ^ u32::from(byte))
+        }))
+    }
+}
+
+/// The address we would use to reach the IPv6 internet, if we have one. No packets are sent; the
+/// operating system only picks a route for a well-known public address.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(("2001:4860:4860::8888", 53)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if !ip.is_loopback() && !ip.is_unspecified() && (ip.segments()[0] & 0xffc0) != 0xfe80 => {
+            Some(ip)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackerResponse {
+    /// Seconds we should wait before announcing again.
     pub interval: usize,
-    pub peers: Peers
+
+    /// Seconds we must wait at least before announcing again.
+    #[serde(rename = "min interval", default, skip_serializing_if = "Option::is_none")]
+    pub min_interval: Option<usize>,
+
+    /// To be sent back on our next announce to this tracker.
+    #[serde(rename = "tracker id", default, skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<ByteBuf>,
+
+    /// Something the tracker wants us to know while still answering the request.
+    #[serde(rename = "warning message", default, skip_serializing_if = "Option::is_none")]
+    pub warning_message: Option<String>,
+
+    /// Number of seeders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<usize>,
+
+    /// Number of leechers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<usize>,
+
+    /// Serialized in the compact form unless a peer needs the dictionary model, see [`Peers`].
+    #[serde(default)]
+    pub peers: Peers,
+
+    /// IPv6 peers from BEP 7, which only fit into a separate compact list.
+    #[serde(
+        default,
+        deserialize_with = "peers::deserialize_v6",
+        serialize_with = "peers::serialize_v6",
+        skip_serializing_if = "Peers::is_empty"
+    )]
+    pub peers6: Peers,
+}
+
+impl TrackerResponse {
+    /// IPv4 and IPv6 peers in one list.
+    pub fn all_peers(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.0.iter().chain(&self.peers6.0)
+    }
+
+    /// Socket addresses of all IPv4 and IPv6 peers, see [`Peers::resolve`].
+    pub async fn resolve_peers(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.peers.resolve().await;
+        addrs.extend(self.peers6.resolve().await);
+        addrs
+    }
+}
+
+/// The response of a tracker that refused an announce.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Failure {
+    #[serde(rename = "failure reason")]
+    reason: String,
+}
+
+/// Swarm statistics of a single torrent, as returned by a scrape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent.
+    #[serde(default)]
+    pub complete: usize,
+    /// Number of times the torrent has been downloaded completely.
+    #[serde(default)]
+    pub downloaded: usize,
+    /// Peers still downloading.
+    #[serde(default)]
+    pub incomplete: usize,
+}
+
+/// The response of an HTTP tracker to a scrape, keyed by raw info hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+fn urlencode(t: &[u8; 20]) -> String {
+    let mut encoded = String::with_capacity(3 * t.len());
+    for &byte in t {
+        encoded.push('%');
+        encoded.push_str(&hex::encode([byte]));
+    }
+    encoded
 }
 
 mod peers {
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
+    use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
     use serde::ser::{Serialize, Serializer};
+    use serde_bytes::ByteBuf;
     use std::fmt;
-    use std::net::{SocketAddrV4, Ipv4Addr};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    /// Where a tracker says a peer can be reached.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub enum PeerAddr {
+        Ip(SocketAddr),
+        /// A DNS name that still needs resolving; only non-compact responses carry these.
+        Host(String, u16),
+    }
+
+    impl fmt::Display for PeerAddr {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                PeerAddr::Ip(addr) => write!(f, "{addr}"),
+                PeerAddr::Host(host, port) => write!(f, "{host}:{port}"),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Peer {
+        pub addr: PeerAddr,
+        /// Only known when the tracker sent the non-compact form and included it.
+        pub peer_id: Option<[u8; 20]>,
+    }
+
+    /// A peer list as trackers send it.
+    ///
+    /// Serializes to the compact form when that can represent every peer, that is when all of
+    /// them are IPv4 addresses without a peer id, and to the dictionary model otherwise.
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers(pub Vec<Peer>);
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    impl Peers {
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+
+        /// The compact form of the IPv4 peers; everyone else is left out.
+        pub fn to_compact(&self) -> Vec<u8> {
+            let mut single_slice = Vec::with_capacity(self.0.len() * 6);
+            for peer in &self.0 {
+                if let PeerAddr::Ip(SocketAddr::V4(addr)) = &peer.addr {
+                    single_slice.extend_from_slice(&addr.ip().octets());
+                    single_slice.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+            single_slice
+        }
+
+        /// The compact IPv6 form of BEP 7 of the IPv6 peers; everyone else is left out.
+        pub fn to_compact6(&self) -> Vec<u8> {
+            let mut single_slice = Vec::with_capacity(self.0.len() * 18);
+            for peer in &self.0 {
+                if let PeerAddr::Ip(SocketAddr::V6(addr)) = &peer.addr {
+                    single_slice.extend_from_slice(&addr.ip().octets());
+                    single_slice.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+            single_slice
+        }
+
+        /// Parses the compact form: 4 bytes of IP address followed by 2 bytes of port per peer.
+        pub fn from_compact(v: &[u8]) -> Option<Self> {
+            if !v.len().is_multiple_of(6) {
+                return None;
+            }
+            Some(
+                Peers(
+                    v.chunks_exact(6)
+                        .map(|slice_6| Peer {
+                            addr: PeerAddr::Ip(SocketAddr::new(
+                                Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]).into(),
+                                u16::from_be_bytes([slice_6[4], slice_6[5]]),
+                            )),
+                            peer_id: None,
+                        })
+                        .collect()
+                )
+            )
+        }
+
+        /// Parses the compact IPv6 form of BEP 7: 16 bytes of IP address followed by 2 bytes of
+        /// port per peer.
+        pub fn from_compact6(v: &[u8]) -> Option<Self> {
+            if !v.len().is_multiple_of(18) {
+                return None;
+            }
+            Some(
+                Peers(
+                    v.chunks_exact(18)
+                        .map(|slice_18| {
+                            let ip: [u8; 16] = slice_18[..16].try_into().expect("chunk is 18 bytes");
+                            Peer {
+                                addr: PeerAddr::Ip(SocketAddr::new(
+                                    Ipv6Addr::from(ip).into(),
+                                    u16::from_be_bytes([slice_18[16], slice_18[17]]),
+                                )),
+                                peer_id: None,
+                            }
+                        })
+                        .collect()
+                )
+            )
+        }
+
+        /// Socket addresses of all peers, looking up host names. Peers whose name does not
+        /// resolve are left out.
+        pub async fn resolve(&self) -> Vec<SocketAddr> {
+            let mut addrs = Vec::with_capacity(self.0.len());
+            for peer in &self.0 {
+                match &peer.addr {
+                    PeerAddr::Ip(addr) => addrs.push(*addr),
+                    PeerAddr::Host(host, port) => {
+                        if let Ok(resolved) = tokio::net::lookup_host((host.as_str(), *port)).await {
+                            addrs.extend(resolved);
+                        }
+                    }
+                }
+            }
+            let mut seen = std::collections::HashSet::new();
+            addrs.retain(|addr| seen.insert(*addr));
+            addrs
+        }
+    }
+
+    /// One entry of the non-compact peer list.
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct DictPeer {
+        ip: String,
+        port: u16,
+        #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+        peer_id: Option<ByteBuf>,
+    }
+
+    impl From<&Peer> for DictPeer {
+        fn from(peer: &Peer) -> Self {
+            let (ip, port) = match &peer.addr {
+                PeerAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+                PeerAddr::Host(host, port) => (host.clone(), *port),
+            };
+            DictPeer {
+                ip,
+                port,
+                peer_id: peer.peer_id.map(|id| ByteBuf::from(id.to_vec())),
+            }
+        }
+    }
+
+    impl From<DictPeer> for Peer {
+        fn from(peer: DictPeer) -> Self {
+            let addr = match peer.ip.parse::<IpAddr>() {
+                Ok(ip) => PeerAddr::Ip(SocketAddr::new(ip, peer.port)),
+                Err(_) => PeerAddr::Host(peer.ip, peer.port),
+            };
+            Peer {
+                addr,
+                peer_id: peer.peer_id.and_then(|id| id.as_slice().try_into().ok()),
+            }
+        }
+    }
 
     struct PeersVisitor;
     impl<'de> Visitor<'de> for PeersVisitor {
@@ -46,29 +618,59 @@ mod peers {
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
             formatter.write_str(
-                "6 bytes, The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.",
+                "6 bytes per peer, the first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number, or a list of dictionaries with ip, port and peer id",
             )
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
             where
                 E: de::Error, {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
-            } 
-            Ok(
-                Peers(
-                    v.chunks_exact(6)
-                        .map(|slice_6| {
-                            SocketAddrV4::new(
-                                Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                                u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                            )
-                        })
-                        .collect()
-                )
+            Peers::from_compact(v).ok_or_else(|| E::custom(format!("length is {}", v.len())))
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(peer) = seq.next_element::<DictPeer>()? {
+                peers.push(peer.into());
+            }
+            Ok(Peers(peers))
+        }
+    }
+
+    struct Peers6Visitor;
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str(
+                "18 bytes per peer, the first 16 bytes are the peer's IPv6 address and the last 2 bytes are the peer's port number",
             )
         }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: de::Error, {
+            Peers::from_compact6(v).ok_or_else(|| E::custom(format!("length is {}", v.len())))
+        }
+    }
+
+    /// Deserializes the `peers6` key of an announce response.
+    pub fn deserialize_v6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(Peers6Visitor)
+    }
+
+    /// Serializes the `peers6` key of an announce response; only IPv6 addresses are included.
+    pub fn serialize_v6<S>(peers: &Peers, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&peers.to_compact6())
     }
 
     impl<'de> Deserialize<'de> for Peers {
@@ -85,12 +687,141 @@ mod peers {
         where
             S: Serializer,
         {
-            let mut single_slice = Vec::with_capacity(self.0.len() * 6);
-            for peer in &self.0 {
-                single_slice.extend_from_slice(&peer.ip().octets());
-                single_slice.extend_from_slice(&peer.port().to_be_bytes());
+            let compact = self
+                .0
+                .iter()
+                .all(|peer| peer.peer_id.is_none() && matches!(peer.addr, PeerAddr::Ip(SocketAddr::V4(_))));
+            if !compact {
+                return serializer.collect_seq(self.0.iter().map(DictPeer::from));
             }
-            serializer.serialize_bytes(&single_slice)
+            serializer.serialize_bytes(&self.to_compact())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn parse(body: &[u8]) -> Result<TrackerResponse, serde_bencode::Error> {
+        serde_bencode::from_bytes(body)
+    }
+
+    fn v4(a: u8, b: u8, c: u8, d: u8, port: u16) -> PeerAddr {
+        PeerAddr::Ip(SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port).into())
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let response = parse(b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e").unwrap();
+        assert_eq!(response.interval, 1800);
+        let addrs: Vec<_> = response.peers.0.iter().map(|peer| peer.addr.clone()).collect();
+        assert_eq!(addrs, [v4(127, 0, 0, 1, 6881), v4(10, 0, 0, 2, 80)]);
+        assert!(response.peers.0.iter().all(|peer| peer.peer_id.is_none()));
+    }
+
+    #[test]
+    fn rejects_truncated_compact_peers() {
+        assert!(parse(b"d8:intervali1800e5:peers7:\x7f\x00\x00\x01\x1a\xe1\x0ae").is_err());
+    }
+
+    #[test]
+    fn parses_dictionary_peers() {
+        let body = b"d8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:abcdefghijklmnopqrst4:porti6881eed2:ip15:tracker.example4:porti51413eeee";
+        let response = parse(body).unwrap();
+        assert_eq!(
+            response.peers.0,
+            [
+                Peer {
+                    addr: v4(127, 0, 0, 1, 6881),
+                    peer_id: Some(*b"abcdefghijklmnopqrst"),
+                },
+                Peer {
+                    addr: PeerAddr::Host("tracker.example".to_string(), 51413),
+                    peer_id: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn serializes_compact_unless_a_peer_needs_the_dictionary_model() {
+        let compact = Peers(vec![Peer {
+            addr: v4(127, 0, 0, 1, 6881),
+            peer_id: None,
+        }]);
+        assert_eq!(serde_bencode::to_bytes(&compact).unwrap(), b"6:\x7f\x00\x00\x01\x1a\xe1");
+
+        let named = Peers(vec![Peer {
+            addr: PeerAddr::Host("peer.example".to_string(), 80),
+            peer_id: None,
+        }]);
+        let encoded = serde_bencode::to_bytes(&named).unwrap();
+        assert_eq!(encoded, b"ld2:ip12:peer.example4:porti80eee");
+    }
+
+    #[test]
+    fn parses_peers6() {
+        let mut body = b"d8:intervali1800e5:peers0:6:peers618:".to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&6881u16.to_be_bytes());
+        body.push(b'e');
+        let response = parse(&body).unwrap();
+        assert!(response.peers.is_empty());
+        let addrs: Vec<_> = response.all_peers().map(|peer| peer.addr.clone()).collect();
+        assert_eq!(addrs, [PeerAddr::Ip(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 6881))]);
+    }
+
+    #[test]
+    fn rejects_truncated_peers6() {
+        assert!(parse(b"d8:intervali1800e5:peers0:6:peers66:\x7f\x00\x00\x01\x1a\xe1e").is_err());
+    }
+
+    #[test]
+    fn compact6_round_trips_and_leaves_out_ipv4() {
+        let ipv6 = Peer {
+            addr: PeerAddr::Ip(SocketAddr::new("2001:db8::1".parse::<Ipv6Addr>().unwrap().into(), 51413)),
+            peer_id: None,
+        };
+        let peers = Peers(vec![
+            Peer {
+                addr: v4(127, 0, 0, 1, 6881),
+                peer_id: None,
+            },
+            ipv6.clone(),
+        ]);
+        let compact = peers.to_compact6();
+        assert_eq!(compact.len(), 18);
+        assert_eq!(Peers::from_compact6(&compact).unwrap().0, [ipv6]);
+    }
+
+
+    fn http_tracker(url: &str) -> HttpTracker {
+        HttpTracker::new(Url::parse(url).unwrap(), Duration::from_secs(1)).unwrap()
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        let tracker = http_tracker("http://tracker.example/announce.php?passkey=abc");
+        let url = tracker.scrape_url(&[[0xab; 20], [0x01; 20]]).unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!(
+                "http://tracker.example/scrape.php?passkey=abc&info_hash={}&info_hash={}",
+                "%ab".repeat(20),
+                "%01".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn scrape_url_needs_announce_in_the_last_segment() {
+        for url in ["http://tracker.example/announce/x", "http://tracker.example/a"] {
+            assert!(matches!(
+                http_tracker(url).scrape_url(&[[0; 20]]),
+                Err(TrackerError::ScrapeUnsupported(_))
+            ));
         }
     }
 }
@@ -0,0 +1,232 @@
+//! Keeps trackers informed over the lifetime of a download.
+
+use super::{Event, Tracker, TrackerClient, TrackerError, TrackerRequest, TrackerResponse};
+use crate::download::{DiscoveredPeer, PeerSource, Progress};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+
+/// We never announce more often than this, whatever the tracker asks for.
+const MIN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the announces on the way out may take altogether. Nobody waits on their answers, so
+/// a tracker that is down must not hold up shutdown either.
+const FAREWELL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Announces a download to every tier of trackers at once: `started` when it begins, again
+/// whenever a tracker's interval has passed, `completed` once the last piece is verified and
+/// `stopped` when it ends. Peers from every response are handed to the download engine as they
+/// arrive.
+///
+/// Within a tier, trackers are tried one after the other until one answers, as BEP 12 asks.
+/// Trackers that are [backing off](super::TrackerHealth::is_backing_off) go last, and the one
+/// that answered goes first from then on.
+pub struct Announcer {
+    shutdown: watch::Sender<bool>,
+    tasks: JoinSet<()>,
+}
+
+impl Announcer {
+    /// Sends the `started` announce to all `tiers` concurrently and keeps announcing to each of
+    /// them in the background until [`stop`](Announcer::stop) is called or the announcer is
+    /// dropped.
+    ///
+    /// The totals in `request` are replaced with those of `progress` on every announce. Returns
+    /// as soon as the first tracker has answered, with its response, so that callers can start
+    /// connecting right away; peers from it have already been sent to `peers`. If every tracker
+    /// failed, one of the errors is returned, a [transient](TrackerError::is_transient) one if
+    /// there is any. Trackers that failed get `started` again once their back-off has passed.
+    pub async fn start(
+        tiers: Vec<Vec<Tracker>>,
+        info_hash: [u8; 20],
+        request: TrackerRequest,
+        progress: Arc<Progress>,
+        peers: mpsc::UnboundedSender<DiscoveredPeer>,
+    ) -> (Self, Result<TrackerResponse, TrackerError>) {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let mut tasks = JoinSet::new();
+        let mut firsts = FuturesUnordered::new();
+        for tier in tiers.into_iter().filter(|tier| !tier.is_empty()) {
+            let session = Session {
+                tier,
+                info_hash,
+                request: request.clone(),
+                progress: Arc::clone(&progress),
+                peers: peers.clone(),
+            };
+            let (first, first_rx) = oneshot::channel();
+            tasks.spawn(session.run(first, shutdown_rx.clone()));
+            firsts.push(first_rx);
+        }
+
+        let mut failure: Option<TrackerError> = None;
+        while let Some(first) = firsts.next().await {
+            match first.expect("sessions report their first announce") {
+                Ok(response) => return (Self { shutdown, tasks }, Ok(response)),
+                Err(e) if failure.as_ref().is_none_or(|failure| !failure.is_transient()) => {
+                    failure = Some(e);
+                }
+                Err(_) => {}
+            }
+        }
+        let failure = failure.unwrap_or(TrackerError::NoTrackers);
+        (Self { shutdown, tasks }, Err(failure))
+    }
+
+    /// Tells the trackers we are leaving the swarm, cutting short announces still in flight, and
+    /// waits a few seconds at most for them to answer.
+    pub async fn stop(mut self) {
+        let _ = self.shutdown.send(true);
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+struct Session {
+    /// Never empty; the tracker that answered last comes first.
+    tier: Vec<Tracker>,
+    info_hash: [u8; 20],
+    request: TrackerRequest,
+    progress: Arc<Progress>,
+    peers: mpsc::UnboundedSender<DiscoveredPeer>,
+}
+
+impl Session {
+    async fn run(
+        mut self,
+        first: oneshot::Sender<Result<TrackerResponse, TrackerError>>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let mut first = Some(first);
+        // Failed announces of `started` and `completed` are sent again with the next attempt.
+        let mut started = false;
+        // Trackers count completed downloads, so only report one that actually happened here.
+        let mut completion_pending = self.progress.left() > 0;
+        // The download finished, but the tracker has yet to hear so.
+        let mut completion_due = false;
+        let mut next = Instant::now();
+        loop {
+            tokio::select! {
+                // A download that finishes right before shutdown still reports its completion.
+                biased;
+                _ = self.progress.completed(), if completion_pending => {
+                    completion_pending = false;
+                    completion_due = true;
+                    // A tracker that never heard `started` learns about us from that first.
+                    if !started {
+                        continue;
+                    }
+                }
+                // A dropped announcer shuts down just like a stopped one.
+                _ = shutdown.wait_for(|&stop| stop) => break,
+                _ = tokio::time::sleep_until(next.into()) => {}
+            }
+            let event = if !started {
+                Some(Event::Started)
+            } else if completion_due {
+                Some(Event::Completed)
+            } else {
+                None
+            };
+            let result = tokio::select! {
+                result = self.announce(event) => result,
+                _ = shutdown.wait_for(|&stop| stop) => break,
+            };
+            if result.is_ok() {
+                match event {
+                    Some(Event::Started) => started = true,
+                    Some(Event::Completed) => completion_due = false,
+                    _ => {}
+                }
+            }
+            next = Instant::now()
+                + if result.is_ok() && completion_due {
+                    Duration::ZERO
+                } else {
+                    self.next_announce(&result)
+                };
+            if let Some(first) = first.take() {
+                // Another tracker may have answered first.
+                let _ = first.send(result);
+            }
+        }
+
+        // Trackers that never acknowledged `started` do not know us, so there is nobody to say
+        // goodbye to.
+        if !started {
+            return;
+        }
+        let farewell = async {
+            if completion_due {
+                let _ = self.announce_once(Event::Completed).await;
+            }
+            let _ = self.announce_once(Event::Stopped).await;
+        };
+        let _ = tokio::time::timeout(FAREWELL_TIMEOUT, farewell).await;
+    }
+
+    async fn announce(&mut self, event: Option<Event>) -> Result<TrackerResponse, TrackerError> {
+        self.update_request(event);
+        let mut order: Vec<usize> = (0..self.tier.len()).collect();
+        // Stable, so that trackers doing equally well keep their place in the tier.
+        order.sort_by_key(|&tracker_i| self.tier[tracker_i].health().is_backing_off());
+        let mut failure = None;
+        for tracker_i in order {
+            match self.tier[tracker_i].announce(self.info_hash, &self.request).await {
+                Ok(response) => {
+                    self.tier[..=tracker_i].rotate_right(1);
+                    return Ok(self.answered(response).await);
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        Err(failure.expect("tiers are never empty"))
+    }
+
+    /// Takes note of the response of the tracker first in the tier.
+    async fn answered(&mut self, response: TrackerResponse) -> TrackerResponse {
+        if let Some(tracker_id) = &response.tracker_id {
+            self.request.trackerid = Some(String::from_utf8_lossy(tracker_id).into_owned());
+        }
+        for addr in response.resolve_peers().await {
+            // The download finishing first is no reason to stop announcing.
+            let _ = self.peers.send(DiscoveredPeer {
+                addr,
+                source: PeerSource::Tracker(self.tier[0].url().to_string()),
+            });
+        }
+        response
+    }
+
+    /// An announce on the way out, to the tracker we last talked to: tried once, and its peers
+    /// are of no use anymore.
+    async fn announce_once(&mut self, event: Event) -> Result<TrackerResponse, TrackerError> {
+        self.update_request(Some(event));
+        self.tier[0].announce_once(self.info_hash, &self.request).await
+    }
+
+    fn update_request(&mut self, event: Option<Event>) {
+        self.request.event = event;
+        self.request.downloaded = self.progress.downloaded() as usize;
+        self.request.uploaded = self.progress.uploaded() as usize;
+        self.request.left = self.progress.left() as usize;
+    }
+
+    /// The tracker's interval, but never less than its minimum interval or ours; after a failure,
+    /// until the first tracker of the tier is done backing off.
+    fn next_announce(&self, result: &Result<TrackerResponse, TrackerError>) -> Duration {
+        match result {
+            Ok(response) => {
+                let interval = response.interval.max(response.min_interval.unwrap_or(0));
+                Duration::from_secs(interval as u64).max(MIN_INTERVAL)
+            }
+            Err(_) => self
+                .tier
+                .iter()
+                .map(|tracker| tracker.health().retry_in())
+                .min()
+                .unwrap_or_default(),
+        }
+    }
+}
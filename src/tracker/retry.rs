@@ -0,0 +1,131 @@
+//! Retries and failure bookkeeping on top of any [`TrackerClient`].
+
+use super::{ScrapeStats, TrackerClient, TrackerError, TrackerRequest, TrackerResponse};
+use futures_util::future::BoxFuture;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Wait before the first immediate retry of a request; it doubles with every further one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Wait after the first failed request before the tracker is worth asking again; it doubles with
+/// every consecutive failure up to [`MAX_BACKOFF`].
+const BACKOFF: Duration = Duration::from_secs(60);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How a tracker has been doing lately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrackerHealth {
+    /// Requests that failed, even after retrying, since the last one that succeeded.
+    pub consecutive_failures: u32,
+    pub last_success: Option<Instant>,
+    /// Until when the tracker should be left alone after its last failure.
+    pub backoff_until: Option<Instant>,
+}
+
+impl TrackerHealth {
+    /// Whether the tracker failed recently enough that it should not be asked yet.
+    pub fn is_backing_off(&self) -> bool {
+        self.backoff_until
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// How long until the tracker is worth asking again; zero if it is now.
+    pub fn retry_in(&self) -> Duration {
+        self.backoff_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()))
+    }
+
+    fn record(&mut self, succeeded: bool) {
+        if succeeded {
+            *self = TrackerHealth {
+                last_success: Some(Instant::now()),
+                ..TrackerHealth::default()
+            };
+        } else {
+            self.consecutive_failures += 1;
+            let backoff = BACKOFF
+                .saturating_mul(1 << (self.consecutive_failures - 1).min(16))
+                .min(MAX_BACKOFF);
+            self.backoff_until = Some(Instant::now() + backoff);
+        }
+    }
+}
+
+/// A tracker client that retries transient failures with exponential back-off and keeps track
+/// of its [`TrackerHealth`], so that callers can put flaky trackers last instead of giving up.
+pub struct Tracker {
+    url: String,
+    client: Box<dyn TrackerClient>,
+    retries: u32,
+    health: Mutex<TrackerHealth>,
+}
+
+impl Tracker {
+    /// Retries a failed request up to `retries` times, waiting one, two, four, ... seconds.
+    pub fn new(url: impl Into<String>, client: Box<dyn TrackerClient>, retries: u32) -> Self {
+        Self {
+            url: url.into(),
+            client,
+            retries,
+            health: Mutex::new(TrackerHealth::default()),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn health(&self) -> TrackerHealth {
+        *self.health.lock().expect("tracker health lock poisoned")
+    }
+
+    /// Announces without retrying, for announces whose answer nobody waits for.
+    pub async fn announce_once(
+        &self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        self.with_retries(0, || self.client.announce(info_hash, request)).await
+    }
+
+    async fn with_retries<'a, T>(
+        &'a self,
+        retries: u32,
+        request: impl Fn() -> BoxFuture<'a, Result<T, TrackerError>>,
+    ) -> Result<T, TrackerError> {
+        let mut attempt = 0;
+        let result = loop {
+            match request().await {
+                Err(e) if e.is_transient() && attempt < retries => {
+                    tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        self.health
+            .lock()
+            .expect("tracker health lock poisoned")
+            .record(result.is_ok());
+        result
+    }
+}
+
+impl TrackerClient for Tracker {
+    fn announce<'a>(
+        &'a self,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, TrackerError>> {
+        Box::pin(self.with_retries(self.retries, move || self.client.announce(info_hash, request)))
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<Vec<ScrapeStats>, TrackerError>> {
+        Box::pin(self.with_retries(self.retries, move || self.client.scrape(info_hashes)))
+    }
+}
@@ -0,0 +1,222 @@
+//! A tracker of our own, for local testing and small private swarms.
+//!
+//! Swarms live in memory only. Peers that stop announcing for longer than
+//! [`TrackerConfig::peer_timeout`] are forgotten.
+
+use super::{Event, Peer, PeerAddr, Peers, ScrapeStats, TrackerError, TrackerRequest, TrackerResponse};
+use rand::seq::IteratorRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub use http::serve as serve_http;
+pub use udp::serve as serve_udp;
+
+mod http;
+mod udp;
+
+/// Peers handed out per announce when the client does not ask for a number.
+const DEFAULT_NUMWANT: usize = 50;
+
+/// Upper bound on peers handed out per announce, whatever the client asks for.
+const MAX_NUMWANT: usize = 200;
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// How long clients should wait between announces.
+    pub interval: Duration,
+    /// How long clients must wait at least between announces.
+    pub min_interval: Duration,
+    /// A peer that has not announced for this long is dropped from its swarm.
+    pub peer_timeout: Duration,
+    /// If set, only these info hashes are tracked and everything else is refused.
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+}
+
+impl TrackerConfig {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+    /// Expects announces every `interval`, forgetting peers that miss two of them.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            min_interval: interval / 2,
+            peer_timeout: interval * 2 + Duration::from_secs(60),
+            whitelist: None,
+        }
+    }
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_INTERVAL)
+    }
+}
+
+/// The swarms of every torrent we track, shared by the HTTP and UDP front ends.
+#[derive(Debug)]
+pub struct SwarmStore {
+    config: TrackerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], Member>,
+    /// Number of `completed` events we have seen.
+    downloaded: usize,
+}
+
+#[derive(Debug)]
+struct Member {
+    /// The address the peer announced from, plus the IPv6 address it told us about, if any.
+    addrs: Vec<SocketAddr>,
+    seeding: bool,
+    last_seen: Instant,
+}
+
+impl Swarm {
+    fn expire(&mut self, peer_timeout: Duration) {
+        self.peers
+            .retain(|_, member| member.last_seen.elapsed() < peer_timeout);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|member| member.seeding).count();
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() - complete,
+        }
+    }
+}
+
+impl SwarmStore {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            swarms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &TrackerConfig {
+        &self.config
+    }
+
+    fn check_allowed(&self, info_hash: &[u8; 20]) -> Result<(), TrackerError> {
+        match &self.config.whitelist {
+            Some(whitelist) if !whitelist.contains(info_hash) => Err(TrackerError::Failure(
+                "torrent is not tracked here".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records an announce that arrived from `from` and picks peers for the announcing client.
+    /// Clients that announce port 0 are not added to the swarm.
+    ///
+    /// For compact requests IPv4 peers go to `peers` and IPv6 peers to `peers6`; otherwise all of
+    /// them go to `peers` in the dictionary model, with their peer ids.
+    pub fn announce(
+        &self,
+        info_hash: [u8; 20],
+        request: &TrackerRequest,
+        from: IpAddr,
+    ) -> Result<TrackerResponse, TrackerError> {
+        self.check_allowed(&info_hash)?;
+
+        let mut swarms = self.swarms.lock().expect("swarm store lock poisoned");
+        let swarm = swarms.entry(info_hash).or_default();
+        swarm.expire(self.config.peer_timeout);
+        if request.event == Some(Event::Completed) {
+            swarm.downloaded += 1;
+        }
+        // Port 0 is how clients that do not accept connections announce; they only get peers.
+        if request.event == Some(Event::Stopped) || request.port == 0 {
+            swarm.peers.remove(&request.peer_id);
+        } else {
+            let mut addrs = vec![SocketAddr::new(from.to_canonical(), request.port)];
+            if let Some(ipv6) = request.ipv6 {
+                let addr = SocketAddr::new(ipv6.into(), request.port);
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+            swarm.peers.insert(
+                request.peer_id,
+                Member {
+                    addrs,
+                    seeding: request.left == 0,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        let numwant = match request.event {
+            Some(Event::Stopped) => 0,
+            _ => request.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT),
+        };
+        let chosen = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != request.peer_id)
+            .choose_multiple(&mut rand::thread_rng(), numwant);
+        let compact = request.compact != 0;
+        let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+        for (peer_id, member) in chosen {
+            for &addr in &member.addrs {
+                let peer = Peer {
+                    addr: PeerAddr::Ip(addr),
+                    peer_id: (!compact).then_some(*peer_id),
+                };
+                if compact && addr.is_ipv6() {
+                    peers6.push(peer);
+                } else {
+                    peers.push(peer);
+                }
+            }
+        }
+
+        let stats = swarm.stats();
+        // Swarms with completed downloads are kept around for their scrape statistics.
+        if swarm.peers.is_empty() && swarm.downloaded == 0 {
+            swarms.remove(&info_hash);
+        }
+        Ok(TrackerResponse {
+            interval: self.config.interval.as_secs() as usize,
+            min_interval: Some(self.config.min_interval.as_secs() as usize),
+            tracker_id: None,
+            warning_message: None,
+            complete: Some(stats.complete),
+            incomplete: Some(stats.incomplete),
+            peers: Peers(peers),
+            peers6: Peers(peers6),
+        })
+    }
+
+    /// Statistics for each of `info_hashes`, or for every tracked torrent if there are none.
+    /// Torrents we know nothing about come back as empty swarms.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<([u8; 20], ScrapeStats)>, TrackerError> {
+        for info_hash in info_hashes {
+            self.check_allowed(info_hash)?;
+        }
+        let mut swarms = self.swarms.lock().expect("swarm store lock poisoned");
+        for swarm in swarms.values_mut() {
+            swarm.expire(self.config.peer_timeout);
+        }
+        if info_hashes.is_empty() {
+            return Ok(swarms
+                .iter()
+                .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+                .collect());
+        }
+        Ok(info_hashes
+            .iter()
+            .map(|info_hash| {
+                let stats = swarms.get(info_hash).map(Swarm::stats).unwrap_or_default();
+                (*info_hash, stats)
+            })
+            .collect())
+    }
+}
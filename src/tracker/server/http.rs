@@ -0,0 +1,146 @@
+//! The HTTP front end of the tracker: `GET /announce` and `GET /scrape`.
+
+use super::SwarmStore;
+use crate::tracker::{Failure, ScrapeResponse, TrackerError, TrackerRequest};
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Longest request head we accept; announces are a single short line.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers tracker requests on `listener` until accepting a connection fails.
+pub async fn serve(listener: TcpListener, store: Arc<SwarmStore>) -> std::io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let store = Arc::clone(&store);
+        tokio::spawn(async move {
+            // A client that goes away mid-request is its own problem.
+            let _ = handle(stream, addr, &store).await;
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, addr: SocketAddr, store: &SwarmStore) -> std::io::Result<()> {
+    let Ok(head) = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await else {
+        return Ok(());
+    };
+    let head = head?;
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let (status, body) = if method != "GET" {
+        ("405 Method Not Allowed", failure("only GET is supported"))
+    } else {
+        let answer = match path {
+            "/announce" => announce(query, addr.ip(), store),
+            "/scrape" => scrape(query, store),
+            _ => return respond(&mut stream, "404 Not Found", &failure("not found")).await,
+        };
+        // Clients look for the failure reason in the body, whatever the status.
+        let body = answer.unwrap_or_else(|e| match e {
+            TrackerError::Failure(reason) => failure(&reason),
+            e => failure(&e.to_string()),
+        });
+        ("200 OK", body)
+    };
+    respond(&mut stream, status, &body).await
+}
+
+/// Reads up to and including the blank line that ends the request head.
+async fn read_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request too long"));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+fn announce(query: &str, from: IpAddr, store: &SwarmStore) -> Result<Vec<u8>, TrackerError> {
+    let invalid = |what: &str| TrackerError::Failure(format!("missing or invalid {what}"));
+    // The binary parameters are percent-decoded by hand; the rest maps onto the request struct,
+    // which ignores the parameters it does not know.
+    let info_hash = binary_param(query, "info_hash").ok_or_else(|| invalid("info_hash"))?;
+    let peer_id = binary_param(query, "peer_id").ok_or_else(|| invalid("peer_id"))?;
+    let mut request: TrackerRequest =
+        serde_urlencoded::from_str(query).map_err(|e| TrackerError::Failure(e.to_string()))?;
+    request.peer_id = peer_id;
+    let response = store.announce(info_hash, &request, from)?;
+    Ok(serde_bencode::to_bytes(&response)?)
+}
+
+fn scrape(query: &str, store: &SwarmStore) -> Result<Vec<u8>, TrackerError> {
+    let info_hashes = binary_params(query, "info_hash")
+        .map(|info_hash| {
+            info_hash.ok_or_else(|| TrackerError::Failure("invalid info_hash".to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let files = store
+        .scrape(&info_hashes)?
+        .into_iter()
+        .map(|(info_hash, stats)| (ByteBuf::from(info_hash.to_vec()), stats))
+        .collect();
+    Ok(serde_bencode::to_bytes(&ScrapeResponse { files })?)
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    serde_bencode::to_bytes(&Failure {
+        reason: reason.to_string(),
+    })
+    .expect("a failure always encodes")
+}
+
+/// The first 20-byte value of `name` in `query`.
+fn binary_param(query: &str, name: &str) -> Option<[u8; 20]> {
+    binary_params(query, name).next()?
+}
+
+/// Every value of `name` in `query`, or `None` for values that do not decode to 20 bytes.
+fn binary_params<'q>(query: &'q str, name: &'q str) -> impl Iterator<Item = Option<[u8; 20]>> + 'q {
+    query
+        .split('&')
+        .filter_map(move |pair| pair.strip_prefix(name)?.strip_prefix('='))
+        .map(|value| percent_decode(value)?.try_into().ok())
+}
+
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b'+' => b' ',
+            byte => byte,
+        });
+    }
+    Some(decoded)
+}
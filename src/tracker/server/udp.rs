@@ -0,0 +1,285 @@
+//! The UDP front end of the tracker from BEP 15.
+
+use super::SwarmStore;
+use crate::token::WindowedToken;
+use crate::tracker::udp::{
+    be_u32, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE,
+    CONNECTION_ID_LIFETIME, MAX_SCRAPE_HASHES, PROTOCOL_ID,
+};
+use crate::tracker::{Event, TrackerError, TrackerRequest};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// Size of an announce request up to and including the port.
+const ANNOUNCE_LEN: usize = 98;
+
+/// Answers tracker requests on `socket` until receiving fails.
+pub async fn serve(socket: UdpSocket, store: Arc<SwarmStore>) -> std::io::Result<()> {
+    // Connection ids prove that a client can receive at its source address.
+    let connections = WindowedToken::new(CONNECTION_ID_LIFETIME);
+    let mut buf = vec![0; 2048];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        if let Some(reply) = handle(&buf[..n], from, &store, &connections) {
+            // The client retransmits if the reply gets lost.
+            let _ = socket.send_to(&reply, from).await;
+        }
+    }
+}
+
+/// Returns the reply to the request in `packet`, or `None` for packets that do not deserve one.
+fn handle(packet: &[u8], from: SocketAddr, store: &SwarmStore, connections: &WindowedToken) -> Option<Vec<u8>> {
+    if packet.len() < 16 {
+        return None;
+    }
+    let connection_id = u64::from_be_bytes(packet[..8].try_into().expect("packet is long enough"));
+    let action = be_u32(&packet[8..12]);
+    let transaction_id = be_u32(&packet[12..16]);
+
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return None;
+        }
+        let mut reply = header(ACTION_CONNECT, transaction_id);
+        reply.extend_from_slice(&connections.issue(from));
+        return Some(reply);
+    }
+    if !connections.is_valid(from, &packet[..8]) {
+        return Some(error(transaction_id, "invalid connection id"));
+    }
+    let answer = match action {
+        ACTION_ANNOUNCE => announce(packet, from, store, transaction_id),
+        ACTION_SCRAPE => scrape(packet, store, transaction_id),
+        other => Err(TrackerError::Failure(format!("unknown action {other}"))),
+    };
+    Some(answer.unwrap_or_else(|e| match e {
+        TrackerError::Failure(reason) => error(transaction_id, &reason),
+        e => error(transaction_id, &e.to_string()),
+    }))
+}
+
+fn announce(
+    packet: &[u8],
+    from: SocketAddr,
+    store: &SwarmStore,
+    transaction_id: u32,
+) -> Result<Vec<u8>, TrackerError> {
+    if packet.len() < ANNOUNCE_LEN {
+        return Err(TrackerError::Failure("announce request is too short".to_string()));
+    }
+    let be_u64 = |at: usize| u64::from_be_bytes(packet[at..at + 8].try_into().expect("length checked"));
+    let info_hash: [u8; 20] = packet[16..36].try_into().expect("length checked");
+    let event = match be_u32(&packet[80..84]) {
+        1 => Some(Event::Completed),
+        2 => Some(Event::Started),
+        3 => Some(Event::Stopped),
+        _ => None,
+    };
+    let num_want = i32::from_be_bytes(packet[92..96].try_into().expect("length checked"));
+    let request = TrackerRequest {
+        peer_id: packet[36..56].try_into().expect("length checked"),
+        port: u16::from_be_bytes([packet[96], packet[97]]),
+        downloaded: be_u64(56) as usize,
+        left: be_u64(64) as usize,
+        uploaded: be_u64(72) as usize,
+        compact: 1,
+        key: be_u32(&packet[88..92]),
+        numwant: usize::try_from(num_want).ok(),
+        // Peers only ever reach us from one address family here, and that is the one we answer
+        // in, so there is no point in a second address.
+        ipv6: None,
+        event,
+        trackerid: None,
+    };
+    let response = store.announce(info_hash, &request, from.ip())?;
+
+    let mut reply = header(ACTION_ANNOUNCE, transaction_id);
+    reply.extend_from_slice(&(response.interval as u32).to_be_bytes());
+    reply.extend_from_slice(&(response.incomplete.unwrap_or(0) as u32).to_be_bytes());
+    reply.extend_from_slice(&(response.complete.unwrap_or(0) as u32).to_be_bytes());
+    // Clients tell the size of the peer entries from the address family they used.
+    if from.ip().to_canonical().is_ipv4() {
+        reply.extend_from_slice(&response.peers.to_compact());
+    } else {
+        reply.extend_from_slice(&response.peers6.to_compact6());
+    }
+    Ok(reply)
+}
+
+fn scrape(packet: &[u8], store: &SwarmStore, transaction_id: u32) -> Result<Vec<u8>, TrackerError> {
+    let info_hashes: Vec<[u8; 20]> = packet[16..]
+        .chunks_exact(20)
+        .take(MAX_SCRAPE_HASHES)
+        .map(|info_hash| info_hash.try_into().expect("chunk is 20 bytes"))
+        .collect();
+    if info_hashes.is_empty() {
+        return Err(TrackerError::Failure("scrape request names no torrent".to_string()));
+    }
+    let mut reply = header(ACTION_SCRAPE, transaction_id);
+    for (_, stats) in store.scrape(&info_hashes)? {
+        reply.extend_from_slice(&(stats.complete as u32).to_be_bytes());
+        reply.extend_from_slice(&(stats.downloaded as u32).to_be_bytes());
+        reply.extend_from_slice(&(stats.incomplete as u32).to_be_bytes());
+    }
+    Ok(reply)
+}
+
+fn header(action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(2048);
+    reply.extend_from_slice(&action.to_be_bytes());
+    reply.extend_from_slice(&transaction_id.to_be_bytes());
+    reply
+}
+
+fn error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = header(ACTION_ERROR, transaction_id);
+    reply.extend_from_slice(message.as_bytes());
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::server::TrackerConfig;
+    use std::time::SystemTime;
+
+    const TRANSACTION_ID: u32 = 0x1234_5678;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn request(connection_id: u64, action: u32) -> Vec<u8> {
+        let mut packet = connection_id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&TRANSACTION_ID.to_be_bytes());
+        packet
+    }
+
+    fn announce_request(connection_id: u64, info_hash: [u8; 20], port: u16) -> Vec<u8> {
+        let mut packet = request(connection_id, ACTION_ANNOUNCE);
+        packet.extend_from_slice(&info_hash);
+        packet.extend_from_slice(&[b'p'; 20]);
+        // Downloaded, left and uploaded.
+        packet.extend_from_slice(&[0; 24]);
+        packet.extend_from_slice(&2u32.to_be_bytes());
+        // IP address and key.
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&(-1i32).to_be_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet
+    }
+
+    fn assert_error(reply: Option<Vec<u8>>, message: &str) {
+        let reply = reply.unwrap();
+        assert_eq!(be_u32(&reply[0..4]), ACTION_ERROR);
+        assert_eq!(be_u32(&reply[4..8]), TRANSACTION_ID);
+        assert_eq!(String::from_utf8_lossy(&reply[8..]), message);
+    }
+
+    struct Setup {
+        store: SwarmStore,
+        connections: WindowedToken,
+        /// A client that connected with `connection_id`.
+        from: SocketAddr,
+        connection_id: u64,
+    }
+
+    impl Setup {
+        fn handle(&self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+            handle(packet, from, &self.store, &self.connections)
+        }
+
+        /// Connects as `from` and returns the connection id the tracker handed out.
+        fn connect(&self, from: SocketAddr) -> u64 {
+            let reply = self.handle(&request(PROTOCOL_ID, ACTION_CONNECT), from).unwrap();
+            assert_eq!(be_u32(&reply[0..4]), ACTION_CONNECT);
+            assert_eq!(be_u32(&reply[4..8]), TRANSACTION_ID);
+            u64::from_be_bytes(reply[8..16].try_into().unwrap())
+        }
+    }
+
+    fn setup() -> Setup {
+        let mut setup = Setup {
+            store: SwarmStore::new(TrackerConfig::default()),
+            connections: WindowedToken::new(CONNECTION_ID_LIFETIME),
+            from: addr("10.0.0.1:6881"),
+            connection_id: 0,
+        };
+        setup.connection_id = setup.connect(setup.from);
+        setup
+    }
+
+    #[test]
+    fn announces_with_an_issued_connection_id() {
+        let tracker = setup();
+        let reply = tracker.handle(&announce_request(tracker.connection_id, [1; 20], 6881), tracker.from).unwrap();
+        assert_eq!(be_u32(&reply[0..4]), ACTION_ANNOUNCE);
+        assert_eq!(be_u32(&reply[4..8]), TRANSACTION_ID);
+        assert_eq!(be_u32(&reply[8..12]), TrackerConfig::DEFAULT_INTERVAL.as_secs() as u32);
+    }
+
+    #[test]
+    fn accepts_connection_ids_from_the_previous_window() {
+        let tracker = setup();
+        let issued = SystemTime::now() - CONNECTION_ID_LIFETIME;
+        let connection_id = u64::from_be_bytes(tracker.connections.issue_at(tracker.from, issued));
+        let reply = tracker.handle(&announce_request(connection_id, [1; 20], 6881), tracker.from).unwrap();
+        assert_eq!(be_u32(&reply[0..4]), ACTION_ANNOUNCE);
+
+        let expired = SystemTime::now() - 2 * CONNECTION_ID_LIFETIME;
+        let connection_id = u64::from_be_bytes(tracker.connections.issue_at(tracker.from, expired));
+        let reply = tracker.handle(&announce_request(connection_id, [1; 20], 6881), tracker.from);
+        assert_error(reply, "invalid connection id");
+    }
+
+    #[test]
+    fn rejects_unknown_connection_ids() {
+        let tracker = setup();
+        for packet in [
+            announce_request(tracker.connection_id ^ 1, [1; 20], 6881),
+            announce_request(PROTOCOL_ID, [1; 20], 6881),
+        ] {
+            assert_error(tracker.handle(&packet, tracker.from), "invalid connection id");
+        }
+        // An id only works from the address it was issued to.
+        let packet = announce_request(tracker.connection_id, [1; 20], 6881);
+        assert_error(tracker.handle(&packet, addr("10.0.0.2:6881")), "invalid connection id");
+        // Nor does another tracker's.
+        let other = WindowedToken::new(CONNECTION_ID_LIFETIME);
+        assert_error(handle(&packet, tracker.from, &tracker.store, &other), "invalid connection id");
+        // None of them made it into the swarm.
+        assert_eq!(tracker.store.scrape(&[[1; 20]]).unwrap()[0].1.complete, 0);
+    }
+
+    #[test]
+    fn ignores_malformed_connects() {
+        let tracker = setup();
+        assert_eq!(tracker.handle(&request(PROTOCOL_ID ^ 1, ACTION_CONNECT), tracker.from), None);
+        assert_eq!(tracker.handle(&request(PROTOCOL_ID, ACTION_CONNECT)[..15], tracker.from), None);
+    }
+
+    #[test]
+    fn refuses_short_announces_and_unknown_actions() {
+        let tracker = setup();
+        let packet = announce_request(tracker.connection_id, [1; 20], 6881);
+        assert_error(tracker.handle(&packet[..ANNOUNCE_LEN - 1], tracker.from), "announce request is too short");
+        assert_error(tracker.handle(&request(tracker.connection_id, 7), tracker.from), "unknown action 7");
+    }
+
+    #[test]
+    fn hands_out_peers_without_adding_clients_on_port_0() {
+        let tracker = setup();
+        let seed = addr("10.0.0.2:6881");
+        let connection_id = tracker.connect(seed);
+        tracker.handle(&announce_request(connection_id, [1; 20], 6881), seed).unwrap();
+
+        let mut packet = announce_request(tracker.connection_id, [1; 20], 0);
+        packet[36..56].copy_from_slice(&[b'q'; 20]);
+        let reply = tracker.handle(&packet, tracker.from).unwrap();
+        assert_eq!(be_u32(&reply[0..4]), ACTION_ANNOUNCE);
+        assert_eq!(&reply[20..], [10, 0, 0, 2, 0x1a, 0xe1]);
+        assert_eq!(tracker.store.scrape(&[[1; 20]]).unwrap()[0].1.complete, 1);
+    }
+}
@@ -0,0 +1,249 @@
+//! The UDP tracker protocol from BEP 15.
+
+use super::{Event, Peers, ScrapeStats, TrackerClient, TrackerError, TrackerRequest, TrackerResponse};
+use futures_util::future::BoxFuture;
+use rand::Rng;
+use reqwest::Url;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+/// Magic constant that identifies a connect request.
+pub(super) const PROTOCOL_ID: u64 = 0x41727101980;
+
+pub(super) const ACTION_CONNECT: u32 = 0;
+pub(super) const ACTION_ANNOUNCE: u32 = 1;
+pub(super) const ACTION_SCRAPE: u32 = 2;
+pub(super) const ACTION_ERROR: u32 = 3;
+
+/// A connection id may be reused for this long after the tracker handed it out.
+pub(super) const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The specification allows for waiting `15 * 2 ^ n` seconds for retransmission `n` up to 8.
+pub const MAX_RETRANSMITS: u32 = 8;
+
+/// Trackers answer scrapes for at most this many info hashes per packet.
+pub(super) const MAX_SCRAPE_HASHES: usize = 74;
+
+/// A tracker that speaks the announce and scrape protocol over UDP.
+#[derive(Debug)]
+pub struct UdpTracker {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    retransmits: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    socket: Option<UdpSocket>,
+    /// Whether we reached the tracker over IPv6, in which case it sends 18-byte peer entries.
+    ipv6: bool,
+    connection: Option<(u64, Instant)>,
+}
+
+impl UdpTracker {
+    /// `timeout` is how long we wait for the first answer; it doubles with every retransmission.
+    pub fn new(url: &Url, timeout: Duration) -> Result<Self, TrackerError> {
+        let invalid = || TrackerError::InvalidUrl(url.to_string());
+        Ok(Self {
+            // IPv6 addresses come in brackets, which name lookups do not understand.
+            host: url
+                .host_str()
+                .ok_or_else(invalid)?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port: url.port().ok_or_else(invalid)?,
+            timeout,
+            retransmits: MAX_RETRANSMITS,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Gives up after `retransmits` unanswered retransmissions instead of [`MAX_RETRANSMITS`].
+    pub fn with_retransmits(mut self, retransmits: u32) -> Self {
+        self.retransmits = retransmits.min(MAX_RETRANSMITS);
+        self
+    }
+
+    async fn connection_id(&self, state: &mut State) -> Result<u64, TrackerError> {
+        if let Some((connection_id, obtained)) = state.connection {
+            if obtained.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(connection_id);
+            }
+        }
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        let response = self.exchange(state, packet, ACTION_CONNECT).await?;
+        let connection_id: [u8; 8] = response
+            .get(..8)
+            .and_then(|id| id.try_into().ok())
+            .ok_or_else(|| TrackerError::Protocol("connect response is too short".to_string()))?;
+        let connection_id = u64::from_be_bytes(connection_id);
+        state.connection = Some((connection_id, Instant::now()));
+        Ok(connection_id)
+    }
+
+    /// Sends `packet` with a fresh transaction id written to bytes 12..16 and retransmits it with
+    /// exponential back-off until a response with the same transaction id arrives. Returns the
+    /// response body after the action and transaction id.
+    async fn exchange(
+        &self,
+        state: &mut State,
+        mut packet: Vec<u8>,
+        action: u32,
+    ) -> Result<Vec<u8>, TrackerError> {
+        if state.socket.is_none() {
+            let tracker = self.resolve().await?;
+            let socket = if tracker.is_ipv4() {
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?
+            } else {
+                UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?
+            };
+            socket.connect(tracker).await?;
+            state.socket = Some(socket);
+            state.ipv6 = tracker.is_ipv6();
+        }
+        let socket = state.socket.as_ref().expect("socket was just bound");
+
+        let transaction_id: u32 = rand::thread_rng().gen();
+        packet[12..16].copy_from_slice(&transaction_id.to_be_bytes());
+        let mut buf = vec![0; 2048];
+        for attempt in 0..=self.retransmits {
+            socket.send(&packet).await?;
+            let deadline = tokio::time::Instant::now() + self.timeout * 2u32.pow(attempt);
+            loop {
+                let n = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                    Ok(received) => received?,
+                    Err(_) => break,
+                };
+                if n < 8 || be_u32(&buf[4..8]) != transaction_id {
+                    // A late answer to an earlier request; keep waiting for ours.
+                    continue;
+                }
+                let body = buf[8..n].to_vec();
+                return match be_u32(&buf[0..4]) {
+                    a if a == action => Ok(body),
+                    ACTION_ERROR => {
+                        // Trackers mostly answer an expired or unknown connection id with an
+                        // error, so connect afresh next time.
+                        state.connection = None;
+                        Err(TrackerError::Failure(String::from_utf8_lossy(&body).into_owned()))
+                    }
+                    other => Err(TrackerError::Protocol(format!("unexpected action {other}"))),
+                };
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    /// Looks up the tracker, preferring an IPv4 address.
+    async fn resolve(&self) -> Result<SocketAddr, TrackerError> {
+        let addrs: Vec<_> = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .collect();
+        addrs
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or_else(|| addrs.first())
+            .copied()
+            .ok_or_else(|| TrackerError::InvalidUrl(format!("udp://{}:{}", self.host, self.port)))
+    }
+}
+
+impl TrackerClient for UdpTracker {
+    fn announce<'a>(
+        &'a self,
+        info_hash: [u8; 20],
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse, TrackerError>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            let connection_id = self.connection_id(&mut state).await?;
+
+            let mut packet = Vec::with_capacity(98);
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend_from_slice(&[0; 4]);
+            packet.extend_from_slice(&info_hash);
+            packet.extend_from_slice(&request.peer_id);
+            packet.extend_from_slice(&(request.downloaded as u64).to_be_bytes());
+            packet.extend_from_slice(&(request.left as u64).to_be_bytes());
+            packet.extend_from_slice(&(request.uploaded as u64).to_be_bytes());
+            let event: u32 = match request.event {
+                None => 0,
+                Some(Event::Completed) => 1,
+                Some(Event::Started) => 2,
+                Some(Event::Stopped) => 3,
+            };
+            packet.extend_from_slice(&event.to_be_bytes());
+            // ip: let the tracker use the source address
+            packet.extend_from_slice(&0u32.to_be_bytes());
+            packet.extend_from_slice(&request.key.to_be_bytes());
+            // -1 leaves it to the tracker
+            let num_want = request.numwant.map_or(-1, |numwant| numwant.min(i32::MAX as usize) as i32);
+            packet.extend_from_slice(&num_want.to_be_bytes());
+            packet.extend_from_slice(&request.port.to_be_bytes());
+
+            let response = self.exchange(&mut state, packet, ACTION_ANNOUNCE).await?;
+            if response.len() < 12 {
+                return Err(TrackerError::Protocol("announce response is too short".to_string()));
+            }
+            let (peers, peers6) = if state.ipv6 {
+                (Some(Peers::default()), Peers::from_compact6(&response[12..]))
+            } else {
+                (Peers::from_compact(&response[12..]), Some(Peers::default()))
+            };
+            let malformed = || TrackerError::Protocol("malformed compact peer list".to_string());
+            Ok(TrackerResponse {
+                interval: be_u32(&response[0..4]) as usize,
+                min_interval: None,
+                tracker_id: None,
+                warning_message: None,
+                complete: Some(be_u32(&response[8..12]) as usize),
+                incomplete: Some(be_u32(&response[4..8]) as usize),
+                peers: peers.ok_or_else(malformed)?,
+                peers6: peers6.ok_or_else(malformed)?,
+            })
+        })
+    }
+
+    fn scrape<'a>(
+        &'a self,
+        info_hashes: &'a [[u8; 20]],
+    ) -> BoxFuture<'a, Result<Vec<ScrapeStats>, TrackerError>> {
+        Box::pin(async move {
+            let mut state = self.state.lock().await;
+            let mut stats = Vec::with_capacity(info_hashes.len());
+            for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+                let connection_id = self.connection_id(&mut state).await?;
+                let mut packet = Vec::with_capacity(16 + 20 * chunk.len());
+                packet.extend_from_slice(&connection_id.to_be_bytes());
+                packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                packet.extend_from_slice(&[0; 4]);
+                for info_hash in chunk {
+                    packet.extend_from_slice(info_hash);
+                }
+                let response = self.exchange(&mut state, packet, ACTION_SCRAPE).await?;
+                if response.len() < 12 * chunk.len() {
+                    return Err(TrackerError::Protocol("scrape response is too short".to_string()));
+                }
+                stats.extend(response.chunks_exact(12).take(chunk.len()).map(|entry| ScrapeStats {
+                    complete: be_u32(&entry[0..4]) as usize,
+                    downloaded: be_u32(&entry[4..8]) as usize,
+                    incomplete: be_u32(&entry[8..12]) as usize,
+                }));
+            }
+            Ok(stats)
+        })
+    }
+}
+
+pub(super) fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().expect("caller checked the length"))
+}